@@ -0,0 +1 @@
+CREATE TABLE embedded_t1 (id INT);
@@ -0,0 +1 @@
+CREATE TABLE embedded_t2 (id INT);
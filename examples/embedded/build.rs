@@ -0,0 +1,3 @@
+fn main() {
+    migrate::embed::embed_migrations("./Migrations").unwrap();
+}
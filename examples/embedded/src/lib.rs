@@ -0,0 +1,6 @@
+//! An application that compiles its migrations into the binary instead of
+//! shipping the `Migrations` directory.
+
+use migrate::EmbeddedMigration;
+
+pub static MIGRATIONS: &[EmbeddedMigration] = migrate::embedded_migrations!();
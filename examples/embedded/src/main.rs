@@ -0,0 +1,13 @@
+use std::process;
+
+use embedded::MIGRATIONS;
+use migrate::console::run_with;
+use migrate::Migrator;
+
+#[tokio::main]
+async fn main() {
+    if let Err(e) = run_with(Migrator::new().embedded(MIGRATIONS)).await {
+        println!("Error occurred: {}", e);
+        process::exit(1);
+    };
+}
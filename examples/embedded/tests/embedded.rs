@@ -0,0 +1,44 @@
+use embedded::MIGRATIONS;
+use migrate::db::{db_connection, run};
+use migrate::{Migrator, RollbackTarget};
+
+const TABLES: [&str; 3] = ["_test_embedded_migrations", "embedded_t1", "embedded_t2"];
+
+async fn drop_tables() {
+    let mut conn = db_connection().await.unwrap();
+    for table in TABLES {
+        let query = format!("DROP TABLE IF EXISTS {}", table);
+        run(&mut conn, query).await.unwrap();
+    }
+}
+
+#[test]
+fn test_embedded_migrations() {
+    let versions: Vec<&str> = MIGRATIONS.iter().map(|m| m.version).collect();
+    assert_eq!(versions, ["2024-01-01_1704067200", "2024-01-02_1704153600"]);
+    assert_eq!(MIGRATIONS[0].up_file, "2024-01-01_1704067200_up.sql");
+    assert_eq!(MIGRATIONS[0].up_sql, "CREATE TABLE embedded_t1 (id INT);\n");
+    assert_eq!(MIGRATIONS[1].down_sql, "DROP TABLE embedded_t2;\n");
+}
+
+#[tokio::test]
+async fn test_run_embedded() {
+    drop_tables().await;
+    let migrator = Migrator::new()
+        .embedded(MIGRATIONS)
+        .table("_test_embedded_migrations");
+
+    migrator.run(None).await.unwrap();
+    let statuses = migrator.status().await.unwrap();
+    assert_eq!(statuses.len(), 2);
+    assert!(statuses.iter().all(|s| s.batch == Some(1)));
+
+    let reverted = migrator
+        .rollback(&RollbackTarget::Steps(u64::MAX))
+        .await
+        .unwrap();
+    assert_eq!(reverted.len(), 2);
+
+    migrator.close().await.unwrap();
+    drop_tables().await;
+}
@@ -0,0 +1,270 @@
+use serde::Deserialize;
+use sqlx::postgres::PgSslMode;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::error::MigrateError;
+use crate::migrator::Migrator;
+
+/// Name of the project settings file.
+pub const CONFIG_FILE: &str = "migrate.toml";
+
+/// Project settings from `migrate.toml`. Anything left out keeps the
+/// default, and command line options take precedence over all of it.
+///
+/// ```toml
+/// dir = "db/migrations"
+/// table = "_migrations"
+/// naming = "%Y%m%d%H%M%S"
+///
+/// [database]
+/// env_file = ".env.production"
+/// sslmode = "verify-full"
+///
+/// [timeouts]
+/// connect = 30
+/// statement = "1min"
+///
+/// [defaults]
+/// atomic = true
+/// ```
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Directory containing the migration files
+    pub dir: Option<String>,
+    pub table: Option<String>,
+    pub schema: Option<String>,
+    /// chrono `strftime` format of the version of new migration files
+    pub naming: Option<String>,
+    #[serde(default)]
+    pub database: DatabaseConfig,
+    #[serde(default)]
+    pub timeouts: TimeoutConfig,
+    #[serde(default)]
+    pub defaults: DefaultsConfig,
+}
+
+/// `[database]`: where to connect, as with `--database-url` or `--env-file`,
+/// and the TLS options.
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub url: Option<String>,
+    pub env_file: Option<String>,
+    pub sslmode: Option<String>,
+    pub sslrootcert: Option<String>,
+    pub sslcert: Option<String>,
+    pub sslkey: Option<String>,
+}
+
+/// `[timeouts]`: `connect` and `lock` in seconds, `db_lock` and `statement`
+/// as PostgreSQL durations.
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TimeoutConfig {
+    pub connect: Option<u64>,
+    pub lock: Option<u64>,
+    pub db_lock: Option<String>,
+    pub statement: Option<String>,
+}
+
+/// `[defaults]`: flags to turn on without passing them.
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct DefaultsConfig {
+    pub verbose: Option<bool>,
+    pub atomic: Option<bool>,
+    pub ignore_checksums: Option<bool>,
+    pub retries: Option<u32>,
+}
+
+impl Config {
+    /// `migrate.toml` in `start` or the closest parent directory that has one.
+    pub fn find(start: &Path) -> Option<PathBuf> {
+        start
+            .ancestors()
+            .map(|dir| dir.join(CONFIG_FILE))
+            .find(|path| path.is_file())
+    }
+
+    /// Read the settings in `path`. Relative paths in it are taken to be
+    /// relative to the directory of the file, not the current directory.
+    pub fn load(path: &Path) -> Result<Config, MigrateError> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| MigrateError::io(&path.display().to_string(), e))?;
+        let mut config: Config = toml::from_str(&contents)
+            .map_err(|e| MigrateError::Config(format!("Invalid {}: {}", path.display(), e)))?;
+
+        let base = path.parent().unwrap_or(Path::new(""));
+        let paths = [
+            &mut config.dir,
+            &mut config.database.env_file,
+            &mut config.database.sslrootcert,
+            &mut config.database.sslcert,
+            &mut config.database.sslkey,
+        ];
+        for path in paths.into_iter().flatten() {
+            *path = base.join(&*path).display().to_string();
+        }
+
+        Ok(config)
+    }
+
+    /// `migrator` with these settings applied.
+    pub fn apply(&self, mut migrator: Migrator) -> Result<Migrator, MigrateError> {
+        if let Some(dir) = &self.dir {
+            migrator = migrator.dir(dir);
+        }
+        if let Some(table) = &self.table {
+            migrator = migrator.table(table);
+        }
+        if let Some(schema) = &self.schema {
+            migrator = migrator.schema(schema);
+        }
+        if let Some(naming) = &self.naming {
+            migrator = migrator.naming(naming);
+        }
+
+        let database = &self.database;
+        match (&database.url, &database.env_file) {
+            (Some(_), Some(_)) => {
+                return Err(MigrateError::Config(format!(
+                    "{} sets both database.url and database.env_file",
+                    CONFIG_FILE
+                )))
+            }
+            (Some(url), None) => migrator = migrator.database_url(url),
+            (None, Some(env_file)) => migrator = migrator.env_file(env_file),
+            (None, None) => {}
+        }
+        if let Some(ssl_mode) = &database.sslmode {
+            let ssl_mode: PgSslMode = ssl_mode.parse().map_err(|e| {
+                MigrateError::Config(format!("Invalid sslmode in {}: {}", CONFIG_FILE, e))
+            })?;
+            migrator = migrator.ssl_mode(ssl_mode);
+        }
+        if let Some(path) = &database.sslrootcert {
+            migrator = migrator.ssl_root_cert(path);
+        }
+        match (&database.sslcert, &database.sslkey) {
+            (Some(cert), Some(key)) => {
+                migrator = migrator.ssl_client_cert(cert).ssl_client_key(key);
+            }
+            (None, None) => {}
+            _ => {
+                return Err(MigrateError::Config(format!(
+                    "{} must set database.sslcert and database.sslkey together",
+                    CONFIG_FILE
+                )))
+            }
+        }
+
+        let timeouts = &self.timeouts;
+        if let Some(seconds) = timeouts.connect {
+            migrator = migrator.connect_timeout(Duration::from_secs(seconds));
+        }
+        if let Some(seconds) = timeouts.lock {
+            migrator = migrator.lock_timeout(Duration::from_secs(seconds));
+        }
+        if let Some(lock_timeout) = &timeouts.db_lock {
+            migrator = migrator.db_lock_timeout(lock_timeout);
+        }
+        if let Some(statement_timeout) = &timeouts.statement {
+            migrator = migrator.statement_timeout(statement_timeout);
+        }
+
+        let defaults = &self.defaults;
+        if let Some(verbose) = defaults.verbose {
+            migrator = migrator.verbose(verbose);
+        }
+        if let Some(atomic) = defaults.atomic {
+            migrator = migrator.atomic(atomic);
+        }
+        if let Some(ignore_checksums) = defaults.ignore_checksums {
+            migrator = migrator.ignore_checksums(ignore_checksums);
+        }
+        if let Some(retries) = defaults.retries {
+            migrator = migrator.retries(retries);
+        }
+
+        Ok(migrator)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_and_load() {
+        let root = std::env::temp_dir().join(format!("migrate_config_{}", std::process::id()));
+        let nested = root.join("src").join("bin");
+        fs::create_dir_all(&nested).unwrap();
+        assert_eq!(Config::find(&nested), None);
+
+        let contents = "dir = \"db/migrations\"\ntable = \"_schema_history\"\n\n\
+            [database]\nenv_file = \"/etc/app/env\"\nsslrootcert = \"certs/ca.pem\"\n\n\
+            [timeouts]\nconnect = 30\nstatement = \"1min\"\n\n\
+            [defaults]\natomic = true\n";
+        fs::write(root.join(CONFIG_FILE), contents).unwrap();
+
+        let path = Config::find(&nested).unwrap();
+        assert_eq!(path, root.join(CONFIG_FILE));
+        let config = Config::load(&path).unwrap();
+        assert_eq!(
+            config.dir,
+            Some(root.join("db/migrations").display().to_string())
+        );
+        assert_eq!(config.table.as_deref(), Some("_schema_history"));
+        assert_eq!(config.database.env_file.as_deref(), Some("/etc/app/env"));
+        assert_eq!(
+            config.database.sslrootcert,
+            Some(root.join("certs/ca.pem").display().to_string())
+        );
+        assert_eq!(config.timeouts.connect, Some(30));
+        assert_eq!(config.timeouts.statement.as_deref(), Some("1min"));
+        assert_eq!(config.defaults.atomic, Some(true));
+        assert!(config.apply(Migrator::new()).is_ok());
+
+        // Misspelled settings are rejected rather than ignored
+        fs::write(root.join(CONFIG_FILE), "[timeouts]\nstatment = \"1min\"\n").unwrap();
+        let err = Config::load(&path).unwrap_err();
+        assert!(err.to_string().contains("statment"));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_apply() {
+        let config = Config {
+            database: DatabaseConfig {
+                url: Some("postgres://localhost/app".to_string()),
+                env_file: Some(".env".to_string()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert!(config.apply(Migrator::new()).is_err());
+
+        let config = Config {
+            database: DatabaseConfig {
+                sslmode: Some("verify".to_string()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let err = config.apply(Migrator::new()).err().unwrap();
+        assert!(err.to_string().contains("sslmode"));
+
+        let config = Config {
+            defaults: DefaultsConfig {
+                ignore_checksums: Some(true),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert!(config.apply(Migrator::new()).unwrap().ignores_checksums());
+    }
+}
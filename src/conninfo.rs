@@ -0,0 +1,295 @@
+use sqlx::postgres::{PgConnectOptions, PgSslMode};
+use std::path::{Path, PathBuf};
+use std::{env, fs, io};
+use url::Url;
+
+use crate::error::MigrateError;
+
+/// Read environment variables from `env_file`, or from `.env` in the current
+/// directory if it exists.
+pub fn load_env(env_file: Option<&str>) -> Result<(), MigrateError> {
+    match env_file {
+        Some(path) => {
+            dotenv::from_path(path)
+                .map_err(|e| MigrateError::Config(format!("Failed to read {}: {}", path, e)))?;
+        }
+        None => {
+            // .env is optional; settings may come from the environment instead
+            dotenv::dotenv().ok();
+        }
+    }
+
+    Ok(())
+}
+
+/// Resolve connection settings the way psql does.
+///
+/// Settings come from, highest precedence first:
+///
+/// 1. `database_url`
+/// 2. the service named by its `service` parameter or PGSERVICE, read from
+///    `pg_service.conf`
+/// 3. PGHOST, PGPORT, PGUSER, PGPASSWORD, PGDATABASE and the other libpq
+///    environment variables
+/// 4. the local Unix socket if there is one, otherwise localhost
+///
+/// A password that is still missing is looked up in `~/.pgpass`, or the file
+/// named by PGPASSFILE. A host starting with `/` is a Unix socket directory.
+pub fn connect_options(database_url: Option<&str>) -> Result<PgConnectOptions, MigrateError> {
+    // Without a URL, the scheme alone leaves every setting to the service
+    // and the environment
+    let mut url = Url::parse(database_url.unwrap_or("postgres:"))
+        .map_err(|e| MigrateError::Config(format!("Invalid database URL: {}", e)))?;
+
+    let service = take_param(&mut url, "service").or_else(|| env::var("PGSERVICE").ok());
+    if let Some(service) = service {
+        add_missing_params(&mut url, &find_service(&service)?);
+    }
+
+    url.as_str()
+        .parse()
+        .map_err(|e| MigrateError::Config(format!("Invalid connection settings: {}", e)))
+}
+
+/// TLS settings that take precedence over the URL and the PGSSL* variables.
+#[derive(Debug, Clone, Default)]
+pub struct TlsOptions {
+    /// `disable`, `allow`, `prefer`, `require`, `verify-ca` or `verify-full`
+    pub ssl_mode: Option<PgSslMode>,
+    /// CA certificate the server certificate must be signed by
+    pub root_cert: Option<String>,
+    /// Certificate and private key to authenticate to the server with
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+}
+
+impl TlsOptions {
+    /// `options` with these settings applied. Certificate files are checked
+    /// here so that a wrong path fails at once instead of on every connection
+    /// attempt.
+    pub fn apply(&self, mut options: PgConnectOptions) -> Result<PgConnectOptions, MigrateError> {
+        if let Some(ssl_mode) = self.ssl_mode {
+            options = options.ssl_mode(ssl_mode);
+        }
+        let files = [
+            ("SSL root certificate", &self.root_cert),
+            ("SSL client certificate", &self.client_cert),
+            ("SSL client key", &self.client_key),
+        ];
+        for (name, path) in files {
+            if let Some(path) = path {
+                if !Path::new(path).is_file() {
+                    return Err(MigrateError::Config(format!(
+                        "{} not found: {}",
+                        name, path
+                    )));
+                }
+            }
+        }
+        if let Some(path) = &self.root_cert {
+            options = options.ssl_root_cert(path);
+        }
+        if let Some(path) = &self.client_cert {
+            options = options.ssl_client_cert(path);
+        }
+        if let Some(path) = &self.client_key {
+            options = options.ssl_client_key(path);
+        }
+
+        Ok(options)
+    }
+}
+
+/// The libpq name of `ssl_mode`, e.g. `verify-full`.
+pub fn ssl_mode_name(ssl_mode: PgSslMode) -> &'static str {
+    match ssl_mode {
+        PgSslMode::Disable => "disable",
+        PgSslMode::Allow => "allow",
+        PgSslMode::Prefer => "prefer",
+        PgSslMode::Require => "require",
+        PgSslMode::VerifyCa => "verify-ca",
+        PgSslMode::VerifyFull => "verify-full",
+    }
+}
+
+/// Remove the query parameter `name` from `url` and return its value.
+fn take_param(url: &mut Url, name: &str) -> Option<String> {
+    let mut value = None;
+    let rest: Vec<(String, String)> = url
+        .query_pairs()
+        .filter_map(|(k, v)| {
+            if k == name {
+                value = Some(v.into_owned());
+                None
+            } else {
+                Some((k.into_owned(), v.into_owned()))
+            }
+        })
+        .collect();
+
+    if value.is_some() {
+        url.set_query(None);
+        if !rest.is_empty() {
+            url.query_pairs_mut().extend_pairs(rest);
+        }
+    }
+    value
+}
+
+/// Add `params` to the query of `url`, except the ones `url` already sets.
+fn add_missing_params(url: &mut Url, params: &[(String, String)]) {
+    let is_set = |url: &Url, key: &str| {
+        let in_url = match key {
+            "host" | "hostaddr" => url.host_str().is_some_and(|h| !h.is_empty()),
+            "port" => url.port().is_some(),
+            "user" => !url.username().is_empty(),
+            "password" => url.password().is_some(),
+            "dbname" => !url.path().trim_start_matches('/').is_empty(),
+            _ => false,
+        };
+        in_url || url.query_pairs().any(|(k, _)| k == key)
+    };
+
+    for (key, value) in params {
+        if !is_set(url, key) {
+            url.query_pairs_mut().append_pair(key, value);
+        }
+    }
+}
+
+/// Settings of the service `name`, from the first file that defines it:
+/// PGSERVICEFILE or `~/.pg_service.conf`, then `pg_service.conf` in
+/// PGSYSCONFDIR.
+fn find_service(name: &str) -> Result<Vec<(String, String)>, MigrateError> {
+    let mut files: Vec<PathBuf> = vec![];
+    match env::var_os("PGSERVICEFILE") {
+        Some(file) => files.push(file.into()),
+        None => {
+            if let Some(home) = env::var_os("HOME") {
+                files.push(Path::new(&home).join(".pg_service.conf"));
+            }
+        }
+    }
+    if let Some(dir) = env::var_os("PGSYSCONFDIR") {
+        files.push(Path::new(&dir).join("pg_service.conf"));
+    }
+
+    for file in &files {
+        let contents = match fs::read_to_string(file) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(MigrateError::io(&file.display().to_string(), e)),
+        };
+        if let Some(params) = parse_service(&contents, name) {
+            return Ok(params);
+        }
+    }
+
+    Err(MigrateError::Config(format!(
+        "Service \"{}\" not found in pg_service.conf",
+        name
+    )))
+}
+
+/// `key=value` lines in the `[name]` section of a service file, or `None` if
+/// there is no such section.
+fn parse_service(contents: &str, name: &str) -> Option<Vec<(String, String)>> {
+    let mut found = false;
+    let mut in_section = false;
+    let mut params = vec![];
+
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(section) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            in_section = section.trim() == name;
+            found |= in_section;
+        } else if in_section {
+            if let Some((key, value)) = line.split_once('=') {
+                params.push((key.trim().to_string(), value.trim().to_string()));
+            }
+        }
+    }
+
+    found.then_some(params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_service() {
+        let contents = "# Services\n[app]\nhost=db.internal\nport = 6432\n\n[other]\nhost=other\n";
+
+        assert_eq!(
+            parse_service(contents, "app").unwrap(),
+            vec![
+                ("host".to_string(), "db.internal".to_string()),
+                ("port".to_string(), "6432".to_string())
+            ]
+        );
+        assert!(parse_service(contents, "missing").is_none());
+    }
+
+    #[test]
+    fn test_service_params() {
+        let mut url =
+            Url::parse("postgres:///app_db?service=prod&user=app&sslmode=require").unwrap();
+        assert_eq!(take_param(&mut url, "service").as_deref(), Some("prod"));
+
+        let params = [
+            ("host", "db.internal"),
+            ("user", "admin"),
+            ("dbname", "other"),
+            ("sslmode", "disable"),
+            ("port", "6432"),
+        ]
+        .map(|(k, v)| (k.to_string(), v.to_string()));
+        add_missing_params(&mut url, &params);
+
+        let options: PgConnectOptions = url.as_str().parse().unwrap();
+        assert_eq!(options.get_host(), "db.internal");
+        assert_eq!(options.get_port(), 6432);
+        assert_eq!(options.get_username(), "app");
+        assert_eq!(options.get_database(), Some("app_db"));
+        assert_eq!(
+            url.query(),
+            Some("user=app&sslmode=require&host=db.internal&port=6432")
+        );
+    }
+
+    #[test]
+    fn test_connect_options() {
+        let options = connect_options(Some("postgres://app@localhost:6432/app_db")).unwrap();
+        assert_eq!(options.get_port(), 6432);
+        assert_eq!(options.get_database(), Some("app_db"));
+
+        let options = connect_options(Some("postgres:///app_db?host=/var/run/postgresql")).unwrap();
+        assert_eq!(
+            options.get_socket().map(|p| p.display().to_string()),
+            Some("/var/run/postgresql".to_string())
+        );
+
+        assert!(connect_options(Some("not a url")).is_err());
+    }
+
+    #[test]
+    fn test_tls_options() {
+        let options = connect_options(Some("postgres://app@localhost/app_db?sslmode=disable"));
+        let tls = TlsOptions {
+            ssl_mode: Some(PgSslMode::VerifyFull),
+            ..Default::default()
+        };
+        let options = tls.apply(options.unwrap()).unwrap();
+        assert!(matches!(options.get_ssl_mode(), PgSslMode::VerifyFull));
+
+        let tls = TlsOptions {
+            root_cert: Some("./no-such-ca.pem".to_string()),
+            ..Default::default()
+        };
+        let err = tls.apply(options).unwrap_err();
+        assert!(err.to_string().contains("no-such-ca.pem"));
+    }
+}
@@ -1,62 +1,504 @@
-use clap::Parser;
-use std::error::Error;
+use clap::{Parser, Subcommand};
+use std::env;
+use std::path::PathBuf;
+use std::time::Duration;
 
 use crate::{
-    db::{create_migration_table, get_executable_query_count, migrate, roolback},
-    file::create_migration_file,
+    config::Config,
+    conninfo::ssl_mode_name,
+    error::MigrateError,
+    migrator::{Diagnosis, Migrator, PlannedMigration, RollbackTarget},
+    status::MigrationState,
 };
 
 #[derive(Debug, Parser)]
+#[command(version, about = "A simple migration tool for PostgreSQL")]
 pub struct Args {
-    #[arg(short = 'c', long = "create", help = "Create migrate files")]
-    create: bool,
+    #[command(subcommand)]
+    command: Command,
 
     #[arg(
-        short = 'i',
-        long = "init",
-        help = "Create migrate table if it doesn't exist."
+        long = "config",
+        value_name = "PATH",
+        help = "Settings file (default: migrate.toml in the current directory or the closest parent that has one)",
+        global = true
     )]
-    init: bool,
+    config: Option<String>,
 
     #[arg(
-        short = 'r',
-        long = "rollback",
-        help = "Rollback database",
-        default_value = "0"
+        long = "dir",
+        value_name = "PATH",
+        help = "Directory containing the migration files (default: ./Migrations)",
+        global = true
     )]
-    rollback: u64,
+    dir: Option<String>,
+
+    #[arg(
+        long = "database-url",
+        value_name = "URL",
+        help = "Database to migrate (default: DATABASE_URL, or the PG* environment variables)",
+        global = true
+    )]
+    database_url: Option<String>,
+
+    #[arg(
+        long = "env-file",
+        value_name = "PATH",
+        conflicts_with = "database_url",
+        help = "File to read environment variables such as DATABASE_URL from (default: .env, if it exists)",
+        global = true
+    )]
+    env_file: Option<String>,
+
+    #[arg(
+        long = "sslmode",
+        value_name = "MODE",
+        value_parser = ["disable", "allow", "prefer", "require", "verify-ca", "verify-full"],
+        help = "TLS mode (default: the URL or PGSSLMODE, otherwise prefer)",
+        global = true
+    )]
+    ssl_mode: Option<String>,
+
+    #[arg(
+        long = "sslrootcert",
+        value_name = "PATH",
+        help = "CA certificate to verify the server with",
+        global = true
+    )]
+    ssl_root_cert: Option<String>,
+
+    #[arg(
+        long = "sslcert",
+        value_name = "PATH",
+        requires = "ssl_client_key",
+        help = "Client certificate to authenticate with",
+        global = true
+    )]
+    ssl_client_cert: Option<String>,
+
+    #[arg(
+        long = "sslkey",
+        value_name = "PATH",
+        requires = "ssl_client_cert",
+        help = "Private key of the client certificate",
+        global = true
+    )]
+    ssl_client_key: Option<String>,
+
+    #[arg(
+        short = 'v',
+        long = "verbose",
+        help = "Print where the database connection goes and whether it is encrypted",
+        global = true
+    )]
+    verbose: bool,
+
+    #[arg(
+        long = "lock-timeout",
+        value_name = "SECONDS",
+        help = "Seconds to wait for another migration run to finish (default: 60)",
+        global = true
+    )]
+    lock_timeout: Option<u64>,
+
+    #[arg(
+        long = "connect-timeout",
+        value_name = "SECONDS",
+        help = "Seconds to keep retrying while the database is not accepting connections (default: 0, or 60 for wait)",
+        global = true
+    )]
+    connect_timeout: Option<u64>,
+
+    #[arg(
+        long = "db-lock-timeout",
+        value_name = "DURATION",
+        help = "PostgreSQL lock_timeout for migration statements, e.g. 5s (default: the server setting)",
+        global = true
+    )]
+    db_lock_timeout: Option<String>,
+
+    #[arg(
+        long = "statement-timeout",
+        value_name = "DURATION",
+        help = "PostgreSQL statement_timeout for migration statements, e.g. 1min (default: the server setting)",
+        global = true
+    )]
+    statement_timeout: Option<String>,
+
+    #[arg(
+        long = "retries",
+        value_name = "COUNT",
+        help = "Retry a migration that hits a lock timeout, serialization failure or deadlock (default: 0)",
+        global = true
+    )]
+    retries: Option<u32>,
+
+    #[arg(
+        long = "schema",
+        help = "Schema of the migration table (default: the search path)",
+        global = true
+    )]
+    schema: Option<String>,
+
+    #[arg(
+        long = "table",
+        help = "Name of the migration table (default: _migrations)",
+        global = true
+    )]
+    table: Option<String>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    #[command(about = "Create migrate table if it doesn't exist.")]
+    Init,
+
+    #[command(about = "Create migrate files")]
+    New,
+
+    #[command(about = "Apply all pending migrations")]
+    Up {
+        #[arg(
+            long = "to",
+            value_name = "VERSION",
+            help = "Stop after applying this migration (e.g. 2024-04-06_1712403500)"
+        )]
+        to: Option<String>,
+
+        #[arg(
+            long = "dry-run",
+            help = "Print the statements that would be executed without running them"
+        )]
+        dry_run: bool,
+
+        #[arg(
+            long = "ignore-checksums",
+            help = "Continue even if applied migration files have been modified"
+        )]
+        ignore_checksums: bool,
+
+        #[arg(
+            long = "atomic",
+            help = "Apply all pending migrations in a single transaction"
+        )]
+        atomic: bool,
+    },
+
+    #[command(about = "Rollback database")]
+    Down {
+        #[arg(
+            short = 'n',
+            long = "steps",
+            help = "Number of migrations to roll back",
+            default_value = "1"
+        )]
+        steps: u64,
+
+        #[arg(
+            long = "to",
+            value_name = "VERSION",
+            conflicts_with = "steps",
+            help = "Roll back every migration applied after this one"
+        )]
+        to: Option<String>,
+
+        #[arg(
+            long = "batch",
+            value_name = "BATCH",
+            num_args = 0..=1,
+            conflicts_with_all = ["steps", "to"],
+            help = "Roll back the latest batch, or this batch and every later one"
+        )]
+        batch: Option<Option<i32>>,
+
+        #[arg(
+            long = "dry-run",
+            help = "Print the statements that would be executed without running them"
+        )]
+        dry_run: bool,
+    },
+
+    #[command(about = "Show applied, pending and missing migrations")]
+    Status {
+        #[arg(
+            long = "ignore-checksums",
+            help = "Continue even if applied migration files have been modified"
+        )]
+        ignore_checksums: bool,
+    },
+
+    #[command(about = "Rollback migrations and apply them again")]
+    Redo {
+        #[arg(
+            short = 'n',
+            long = "steps",
+            help = "Number of migrations to redo",
+            default_value = "1"
+        )]
+        steps: u64,
+    },
+
+    #[command(about = "Rollback all applied migrations")]
+    Reset {
+        #[arg(
+            long = "dry-run",
+            help = "Print the statements that would be executed without running them"
+        )]
+        dry_run: bool,
+    },
+
+    #[command(
+        about = "Wait until the database accepts connections and the migration table is reachable"
+    )]
+    Wait,
+
+    #[command(about = "Check the database connection, its encryption and the migration table")]
+    Doctor,
+
+    #[command(about = "Execute a SQL file outside of migration management")]
+    Exec {
+        #[arg(help = "Path to the SQL file")]
+        path: String,
+    },
+}
+
+pub async fn run() -> Result<(), MigrateError> {
+    run_with(Migrator::new()).await
 }
 
-pub async fn run() -> Result<(), Box<dyn Error>> {
+/// Run the command line against `migrator`, e.g. one built with
+/// `Migrator::embedded` so an application binary can migrate itself.
+pub async fn run_with(migrator: Migrator) -> Result<(), MigrateError> {
     let args = Args::parse();
+    let config_path = match &args.config {
+        Some(path) => Some(PathBuf::from(path)),
+        None => env::current_dir().ok().and_then(|dir| Config::find(&dir)),
+    };
+    let config = match &config_path {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+
+    // migrate.toml overrides the settings `migrator` was built with, and
+    // options given on the command line override both
+    let mut migrator = config.apply(migrator)?;
+    if let Some(dir) = &args.dir {
+        migrator = migrator.dir(dir);
+    }
+    if let Some(database_url) = &args.database_url {
+        migrator = migrator.database_url(database_url);
+    }
+    if let Some(env_file) = &args.env_file {
+        migrator = migrator.env_file(env_file);
+    }
+    if let Some(ssl_mode) = &args.ssl_mode {
+        let ssl_mode = ssl_mode
+            .parse()
+            .map_err(|e| MigrateError::Config(format!("Invalid --sslmode: {}", e)))?;
+        migrator = migrator.ssl_mode(ssl_mode);
+    }
+    if let Some(path) = &args.ssl_root_cert {
+        migrator = migrator.ssl_root_cert(path);
+    }
+    if let Some(path) = &args.ssl_client_cert {
+        migrator = migrator.ssl_client_cert(path);
+    }
+    if let Some(path) = &args.ssl_client_key {
+        migrator = migrator.ssl_client_key(path);
+    }
+    if args.verbose {
+        migrator = migrator.verbose(true);
+    }
+    if let Some(lock_timeout) = args.lock_timeout {
+        migrator = migrator.lock_timeout(Duration::from_secs(lock_timeout));
+    }
+    match (args.connect_timeout, &args.command) {
+        (Some(connect_timeout), _) => {
+            migrator = migrator.connect_timeout(Duration::from_secs(connect_timeout));
+        }
+        (None, Command::Wait) if config.timeouts.connect.is_none() => {
+            migrator = migrator.connect_timeout(Duration::from_secs(60));
+        }
+        _ => {}
+    }
+    if let Some(lock_timeout) = &args.db_lock_timeout {
+        migrator = migrator.db_lock_timeout(lock_timeout);
+    }
+    if let Some(statement_timeout) = &args.statement_timeout {
+        migrator = migrator.statement_timeout(statement_timeout);
+    }
+    if let Some(retries) = args.retries {
+        migrator = migrator.retries(retries);
+    }
+    if let Some(schema) = &args.schema {
+        migrator = migrator.schema(schema);
+    }
+    if let Some(table) = &args.table {
+        migrator = migrator.table(table);
+    }
+    if let Command::Up {
+        ignore_checksums,
+        atomic,
+        ..
+    } = args.command
+    {
+        if ignore_checksums {
+            migrator = migrator.ignore_checksums(true);
+        }
+        if atomic {
+            migrator = migrator.atomic(true);
+        }
+    }
+    if let Some(path) = &config_path {
+        if args.verbose || config.defaults.verbose == Some(true) {
+            println!("Using settings from {}", path.display());
+        }
+    }
 
-    if args.create {
-        create_migration_file().expect("Failed migration files");
-    } else if args.init {
-        create_migration_table().await;
-    } else if args.rollback > 0 {
-        let count = get_executable_query_count(args.rollback).await;
+    // Every step of the command runs on the same connection
+    let result = execute(&migrator, args.command).await;
+    let closed = migrator.close().await;
 
-        if count == 0 {
-            return Err("No targets available for rollback".into());
+    result.and(closed)
+}
+
+async fn execute(migrator: &Migrator, command: Command) -> Result<(), MigrateError> {
+    match command {
+        Command::Init => migrator.init().await?,
+        Command::New => migrator.create()?,
+        Command::Up { to, dry_run, .. } => {
+            if dry_run {
+                print_plan(migrator.plan(to.as_deref()).await?);
+            } else {
+                migrator.run(to.as_deref()).await?;
+            }
+        }
+        Command::Down {
+            steps,
+            to,
+            batch,
+            dry_run,
+        } => {
+            let target = match (to, batch) {
+                (Some(version), _) => RollbackTarget::To(version),
+                (None, Some(batch)) => RollbackTarget::Batch(batch),
+                (None, None) => RollbackTarget::Steps(steps),
+            };
+            rollback(migrator, &target, dry_run).await?;
         }
+        Command::Status { ignore_checksums } => {
+            print_status(migrator, ignore_checksums || migrator.ignores_checksums()).await?
+        }
+        Command::Redo { steps } => migrator.redo(steps).await?,
+        Command::Reset { dry_run } => {
+            rollback(migrator, &RollbackTarget::Steps(u64::MAX), dry_run).await?
+        }
+        Command::Wait => migrator.wait().await?,
+        Command::Doctor => print_diagnosis(migrator.doctor().await?),
+        Command::Exec { path } => migrator.exec(&path).await?,
+    }
 
-        println!("Available execute rollback count: {}", count);
+    Ok(())
+}
 
-        if count > args.rollback {
-            println!(
-                "Limiting rollback to {} due to user requests",
-                args.rollback
-            );
-            roolback(args.rollback)
-                .await
-                .expect("Failed rollback migrations");
+fn print_diagnosis(diagnosis: Diagnosis) {
+    let connection = &diagnosis.connection;
+    println!("{:<16} {}", "Address:", connection.address);
+    println!("{:<16} {}", "Database:", connection.database);
+    println!("{:<16} {}", "User:", connection.user);
+    println!("{:<16} PostgreSQL {}", "Server:", connection.server_version);
+    println!("{:<16} {}", "SSL mode:", ssl_mode_name(connection.ssl_mode));
+    println!("{:<16} {}", "TLS:", connection.tls_label());
+    match diagnosis.schema_version {
+        Some(version) => println!(
+            "{:<16} {} (schema version {}, {} applied migration(s))",
+            "Migration table:", diagnosis.table, version, diagnosis.applied
+        ),
+        None => println!(
+            "{:<16} {} (not created yet)",
+            "Migration table:", diagnosis.table
+        ),
+    }
+}
+
+fn print_plan(plan: Vec<PlannedMigration>) {
+    for migration in plan {
+        let transaction = if migration.transactional {
+            ""
         } else {
-            println!("Executing {} rollbacks as requested", count);
-            roolback(count).await.expect("Failed rollback migrations");
+            ", no transaction"
+        };
+        println!(
+            "-- {} ({} statement(s){})",
+            migration.file,
+            migration.statements.len(),
+            transaction
+        );
+        for (index, statement) in migration.statements.iter().enumerate() {
+            println!("-- [{}]", index + 1);
+            println!("{}", statement);
         }
+    }
+    println!("Dry run: no changes were made");
+}
+
+async fn print_status(migrator: &Migrator, ignore_checksums: bool) -> Result<(), MigrateError> {
+    let statuses = migrator.status().await?;
+    println!(
+        "{:<40} {:<13} {:>5} {:<19} {:>10} {:<8} APPLIED BY",
+        "MIGRATION", "STATE", "BATCH", "APPLIED AT", "DURATION", "VERSION"
+    );
+    for s in &statuses {
+        println!(
+            "{:<40} {:<13} {:>5} {:<19} {:>10} {:<8} {}",
+            s.up_file,
+            s.state,
+            s.batch.map_or("-".to_string(), |b| b.to_string()),
+            s.applied_at_label(),
+            s.duration_label(),
+            s.tool_version.as_deref().unwrap_or("-"),
+            s.applied_by_label()
+        );
+    }
+
+    let count = |state: MigrationState| statuses.iter().filter(|s| s.state == state).count();
+    println!(
+        "\n{} applied, {} modified, {} pending, {} out-of-order, {} file missing",
+        count(MigrationState::Applied),
+        count(MigrationState::Modified),
+        count(MigrationState::Pending),
+        count(MigrationState::OutOfOrder),
+        count(MigrationState::FileMissing)
+    );
+
+    let modified: Vec<String> = statuses
+        .iter()
+        .filter(|s| s.state == MigrationState::Modified)
+        .map(|s| s.up_file.clone())
+        .collect();
+    if !modified.is_empty() && !ignore_checksums {
+        return Err(MigrateError::ModifiedFiles(modified));
+    }
+
+    Ok(())
+}
+
+async fn rollback(
+    migrator: &Migrator,
+    target: &RollbackTarget,
+    dry_run: bool,
+) -> Result<(), MigrateError> {
+    let count = if dry_run {
+        let plan = migrator.plan_rollback(target).await?;
+        let count = plan.len();
+        print_plan(plan);
+        count
     } else {
-        migrate().await.expect("Failed migration");
+        migrator.rollback(target).await?.len()
+    };
+
+    if let (0, RollbackTarget::To(version)) = (count, target) {
+        println!("Nothing to roll back: {} is the latest migration", version);
     }
 
     Ok(())
@@ -1,366 +1,845 @@
-use crate::file::get_all_migration_files;
-use crate::parser::parse_text;
+use crate::conninfo::{connect_options, load_env};
+use crate::error::MigrateError;
+use crate::file::read_sql_file;
+use crate::parser::{parse_directives, parse_text, Directives};
+use crate::retry::RetryPolicy;
+use crate::schema::{set_schema_version, SCHEMA_VERSION};
 use crate::Migrations;
-use sqlx::postgres::{PgPoolOptions, PgQueryResult, PgRow};
-use sqlx::{Pool, Postgres, Row};
-use std::io;
-use std::{env, error::Error, fs};
-
-pub async fn migrate() -> Result<(), Box<dyn Error>> {
-    println!("Start migration");
-    let pool = db_pool().await;
-    let last_migration = get_last_migration(&pool, Migrations::UP).await;
-    let dir = "./Migrations";
-    let all_up_migrations =
-        get_all_migration_files(dir, Migrations::UP).expect("Failed get all migration files");
-    let all_down_migrations =
-        get_all_migration_files(dir, Migrations::DOWN).expect("Failed get all migration file");
-    let start_index = match last_migration {
-        Some(filename) => {
-            all_up_migrations
-                .iter()
-                .position(|m| m == &filename)
-                .unwrap_or(0)
-                + 1
-        }
-        None => 0,
-    };
+use chrono::{DateTime, Utc};
+use gethostname::gethostname;
+use sqlx::postgres::{PgConnectOptions, PgConnection, PgQueryResult, PgRow, PgSslMode};
+use sqlx::{Connection, Decode, Executor, Postgres, Row, Type};
+use std::time::{Duration, Instant};
+use std::{env, fmt, io};
+
+/// Quote `name` as an identifier, e.g. `my "table"` becomes `"my ""table"""`.
+pub fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
 
-    for (index, up_filename) in all_up_migrations.iter().enumerate().skip(start_index) {
-        println!("Processing up migration for {}", &up_filename);
-        let up_path = format!("{}/{}", &dir, &up_filename);
-        let down_filename = all_down_migrations
-            .get(index)
-            .expect("Matching down migration not found");
-        let queries =
-            parse_sql_file(&up_path).expect(&format!("Failed to read {} file", &up_filename));
-        execute_queries(&pool, queries)
-            .await
-            .expect("Query execute failed");
-        insert_migration(&pool, up_filename.clone(), down_filename.clone())
-            .await
-            .expect("Failed to register file in the migration table");
+/// Quoted name of the migration table, qualified with `schema` if given.
+pub fn qualified_table_name(schema: Option<&str>, table: &str) -> String {
+    match schema {
+        Some(schema) => format!("{}.{}", quote_identifier(schema), quote_identifier(table)),
+        None => quote_identifier(table),
     }
-    println!("Migration ended...");
-    Ok(())
 }
 
-pub async fn create_migration_table() {
+/// Create the migration table unless it already exists.
+///
+/// Returns whether the table was created.
+pub async fn create_migration_table(
+    conn: &mut PgConnection,
+    table: &str,
+) -> Result<bool, MigrateError> {
+    let mut tx = conn.begin().await?;
+    let created = ensure_migration_table(&mut tx, table).await?;
+    tx.commit().await?;
+
+    Ok(created)
+}
+
+/// Create the migration table on `conn` unless it already exists, so it can
+/// share a transaction with the first migration.
+pub(crate) async fn ensure_migration_table(
+    conn: &mut PgConnection,
+    table: &str,
+) -> Result<bool, MigrateError> {
+    let exists: bool = sqlx::query("SELECT to_regclass($1) IS NOT NULL")
+        .bind(table)
+        .fetch_one(&mut *conn)
+        .await?
+        .get(0);
+    if exists {
+        return Ok(false);
+    }
+
     // Table definitions for managing migrations
-    let query = "CREATE TABLE _migrations (
+    let query = format!(
+        "CREATE TABLE IF NOT EXISTS {} (
         id SERIAL PRIMARY KEY,
         up_file VARCHAR(400) NOT NULL,
-        down_file VARCHAR(400) NOT NULL
-    );"
-    .to_string();
+        down_file VARCHAR(400) NOT NULL,
+        version VARCHAR(400),
+        applied_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP,
+        up_checksum VARCHAR(64),
+        down_checksum VARCHAR(64),
+        transactional BOOLEAN NOT NULL DEFAULT TRUE,
+        duration_ms BIGINT,
+        applied_by VARCHAR(400) DEFAULT CURRENT_USER,
+        os_user VARCHAR(400),
+        hostname VARCHAR(400),
+        tool_version VARCHAR(64),
+        batch INTEGER
+    );",
+        table
+    );
+
+    sqlx::query(&query).execute(&mut *conn).await?;
+    set_schema_version(conn, table, SCHEMA_VERSION).await?;
+
+    Ok(true)
+}
 
-    run(query).await.expect("Failed migration table");
+/// File name of the most recently applied up or down file, or `None` if no
+/// migration has been applied.
+pub(crate) async fn get_last_migration(
+    conn: &mut PgConnection,
+    table: &str,
+    column_type: Migrations,
+) -> Result<Option<String>, MigrateError> {
+    let query = format!(
+        "SELECT up_file, down_file FROM {} ORDER BY id DESC LIMIT 1",
+        table
+    );
+    let rows = execute_select_query(conn, query).await?;
+
+    Ok(rows.first().map(|row| match column_type {
+        Migrations::UP => row.get("up_file"),
+        Migrations::DOWN => row.get("down_file"),
+    }))
 }
 
-async fn get_last_migration(db: &Pool<Postgres>, column_type: Migrations) -> Option<String> {
-    let query = format!("SELECT up_file, down_file FROM _migrations ORDER BY id DESC LIMIT 1");
-    let result = execute_select_query(db, query).await;
+#[derive(Debug)]
+pub struct AppliedMigration {
+    pub up_file: String,
+    pub down_file: String,
+    pub applied_at: Option<DateTime<Utc>>,
+    pub up_checksum: Option<String>,
+    pub down_checksum: Option<String>,
+    /// False if the up file ran with `-- migrate:no-transaction`
+    pub transactional: bool,
+    /// Time taken to execute the up file
+    pub duration_ms: Option<i64>,
+    /// Database role that applied the migration
+    pub applied_by: Option<String>,
+    pub os_user: Option<String>,
+    pub hostname: Option<String>,
+    /// Version of migrate that applied the migration
+    pub tool_version: Option<String>,
+    /// Number of the run that applied the migration
+    pub batch: Option<i32>,
+}
 
-    match result {
-        Ok(rows) => {
-            if let Some(row) = rows.first() {
-                match column_type {
-                    Migrations::UP => {
-                        let filename: String = row.get("up_file");
-                        Some(filename)
-                    }
-                    Migrations::DOWN => {
-                        let filename: String = row.get("down_file");
-                        Some(filename)
-                    }
-                }
-            } else {
-                None
-            }
-        }
-        Err(e) => {
-            println!("Query failed: {}", e);
-            None
-        }
+pub async fn get_applied_migrations(
+    conn: &mut PgConnection,
+    table: &str,
+) -> Result<Vec<AppliedMigration>, MigrateError> {
+    // Tables created by older versions may lack the columns after down_file
+    let query = format!("SELECT * FROM {} ORDER BY id", table);
+    let rows = execute_select_query(conn, query).await?;
+
+    rows.iter()
+        .map(|row| {
+            Ok(AppliedMigration {
+                up_file: row.try_get("up_file")?,
+                down_file: row.try_get("down_file")?,
+                applied_at: optional_column(row, "applied_at")?,
+                up_checksum: optional_column(row, "up_checksum")?,
+                down_checksum: optional_column(row, "down_checksum")?,
+                transactional: optional_column(row, "transactional")?.unwrap_or(true),
+                duration_ms: optional_column(row, "duration_ms")?,
+                applied_by: optional_column(row, "applied_by")?,
+                os_user: optional_column(row, "os_user")?,
+                hostname: optional_column(row, "hostname")?,
+                tool_version: optional_column(row, "tool_version")?,
+                batch: optional_column(row, "batch")?,
+            })
+        })
+        .collect()
+}
+
+/// Value of `column`, or `None` if it is NULL or the table predates it.
+fn optional_column<'r, T>(row: &'r PgRow, column: &str) -> Result<Option<T>, MigrateError>
+where
+    T: Decode<'r, Postgres> + Type<Postgres>,
+{
+    match row.try_get(column) {
+        Ok(value) => Ok(value),
+        Err(sqlx::Error::ColumnNotFound(_)) => Ok(None),
+        Err(e) => Err(MigrateError::Bookkeeping(e)),
     }
 }
 
-pub async fn insert_migration(
-    db: &Pool<Postgres>,
-    up_file_name: String,
-    down_file_name: String,
-) -> Result<PgQueryResult, Box<dyn Error>> {
-    let query = "INSERT INTO _migrations (up_file, down_file) VALUES ($1, $2)";
+/// A migration that has just been applied, to be recorded in the migration table.
+#[derive(Debug)]
+pub struct NewMigration {
+    pub version: String,
+    pub up_file: String,
+    pub down_file: String,
+    pub up_checksum: String,
+    pub down_checksum: String,
+    pub transactional: bool,
+    pub duration_ms: i64,
+    pub batch: i32,
+}
 
-    let result = sqlx::query(query)
-        .bind(up_file_name)
-        .bind(down_file_name)
+/// Record `migration`, along with the database role, OS user, hostname and
+/// tool version that applied it.
+pub async fn insert_migration<'c, E>(
+    db: E,
+    table: &str,
+    migration: NewMigration,
+) -> Result<PgQueryResult, MigrateError>
+where
+    E: Executor<'c, Database = Postgres>,
+{
+    let query = format!(
+        "INSERT INTO {} (version, up_file, down_file, up_checksum, down_checksum,
+            transactional, duration_ms, applied_by, os_user, hostname, tool_version, batch)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, CURRENT_USER, $8, $9, $10, $11)",
+        table
+    );
+
+    let result = sqlx::query(&query)
+        .bind(migration.version)
+        .bind(migration.up_file)
+        .bind(migration.down_file)
+        .bind(migration.up_checksum)
+        .bind(migration.down_checksum)
+        .bind(migration.transactional)
+        .bind(migration.duration_ms)
+        .bind(os_user())
+        .bind(gethostname().to_string_lossy().into_owned())
+        .bind(env!("CARGO_PKG_VERSION"))
+        .bind(migration.batch)
         .execute(db)
         .await;
 
     result.map_err(|e| e.into())
 }
 
-pub async fn roolback(n: u64) -> Result<(), Box<dyn Error>> {
-    println!("Rolling back {} migration(s)...", n);
-    let pool = db_pool().await;
-    let last_migration = get_last_migration(&pool, Migrations::DOWN).await;
-    let dir = "./Migrations";
-    let mut all_down_migrations =
-        get_all_migration_files(dir, Migrations::DOWN).expect("Failed get all migration files");
-
-    all_down_migrations.sort_by(|a, b| b.cmp(a));
-
-    let start_index = match last_migration.clone() {
-        Some(filename) => all_down_migrations
-            .iter()
-            .position(|m| m == &filename)
-            .unwrap_or(0),
-        None => all_down_migrations.len(),
-    };
-
-    if start_index == all_down_migrations.len() {
-        return Err(Box::new(io::Error::new(
-            io::ErrorKind::NotFound,
-            format!(
-                "Could not find the last migration ran: {}",
-                last_migration.unwrap()
-            ),
-        )));
-    }
-
-    for (index, down_filename) in all_down_migrations
+fn os_user() -> Option<String> {
+    ["USER", "LOGNAME", "USERNAME"]
         .iter()
-        .enumerate()
-        .skip(start_index)
-        .take(n.try_into().unwrap())
-    {
-        println!("Processing down migration for {}", &down_filename);
-        let down_path = format!("{}/{}", &dir, &down_filename);
-        let down_filename = all_down_migrations
-            .get(index)
-            .expect("Matching down migration not found");
-        let queries =
-            parse_sql_file(&down_path).expect(&format!("Failed to read {} file", &down_filename));
-        execute_queries(&pool, queries)
-            .await
-            .expect("Query execute failed");
-        remove_migration(&pool, down_filename.clone())
-            .await
-            .expect("Delete execute failed");
-    }
+        .find_map(|name| env::var(name).ok())
+}
 
-    println!("Rollback completed.");
+/// Batch number for the next run: one more than the latest recorded batch.
+pub async fn next_batch(conn: &mut PgConnection, table: &str) -> Result<i32, MigrateError> {
+    let query = format!("SELECT COALESCE(MAX(batch), 0) + 1 FROM {}", table);
+    let rows = execute_select_query(conn, query).await?;
 
-    Ok(())
+    Ok(rows.first().map(|row| row.get(0)).unwrap_or(1))
 }
 
 pub async fn remove_migration(
-    db: &Pool<Postgres>,
+    conn: &mut PgConnection,
+    table: &str,
     down_filename: String,
-) -> Result<PgQueryResult, Box<dyn Error>> {
-    let query = "DELETE FROM _migrations WHERE down_file = $1";
+) -> Result<PgQueryResult, MigrateError> {
+    let query = format!("DELETE FROM {} WHERE down_file = $1", table);
 
-    let result = sqlx::query(query).bind(down_filename).execute(db).await;
+    let result = sqlx::query(&query).bind(down_filename).execute(conn).await;
 
     result.map_err(|e| e.into())
 }
 
-pub async fn run(query: String) -> Result<(), Box<dyn Error>> {
-    let pool = db_pool().await;
-    execute_query(&pool, query).await;
-    Ok(())
+pub async fn run(conn: &mut PgConnection, query: String) -> Result<(), MigrateError> {
+    execute_query(conn, query).await
+}
+
+/// Connection settings from the environment, after reading `env_file` or a
+/// `.env` file if there is one: DATABASE_URL if it is set, otherwise the
+/// libpq environment variables and service files.
+pub fn env_connect_options(env_file: Option<&str>) -> Result<PgConnectOptions, MigrateError> {
+    load_env(env_file)?;
+    connect_options(env::var("DATABASE_URL").ok().as_deref())
+}
+
+/// Connect using the settings from the environment or a .env file.
+pub async fn db_connection() -> Result<PgConnection, MigrateError> {
+    connect(&env_connect_options(None)?, Duration::ZERO).await
 }
 
-async fn db_pool() -> Pool<Postgres> {
-    dotenv::dotenv().expect("Fialed to read .env file");
-    let database_url = env::var("DATABASE_URL").expect("DABASE_URL must be set");
+/// Open a single connection with `options`, retrying with backoff for up to
+/// `timeout` while the server is not accepting connections yet. A zero
+/// `timeout` tries once.
+///
+/// Rejected credentials fail at once, since waiting will not fix them.
+pub async fn connect(
+    options: &PgConnectOptions,
+    timeout: Duration,
+) -> Result<PgConnection, MigrateError> {
+    if timeout.is_zero() {
+        return PgConnection::connect_with(options)
+            .await
+            .map_err(MigrateError::Connection);
+    }
 
-    let pool = PgPoolOptions::new()
-        .max_connections(10)
-        .connect(&database_url)
+    let backoff = RetryPolicy {
+        max_retries: u32::MAX,
+        base_delay: Duration::from_millis(250),
+        max_delay: Duration::from_secs(5),
+    };
+    let started = Instant::now();
+    let mut retry = 0;
+
+    loop {
+        let remaining = timeout.saturating_sub(started.elapsed());
+        let attempt = tokio::time::timeout(
+            remaining.max(Duration::from_secs(1)),
+            PgConnection::connect_with(options),
+        )
         .await
-        .unwrap_or_else(|_| panic!("Cannot connect to the database"));
-    pool
+        .unwrap_or_else(|_| {
+            let e = io::Error::new(io::ErrorKind::TimedOut, "connection attempt timed out");
+            Err(sqlx::Error::Io(e))
+        });
+        let e = match attempt {
+            Ok(conn) => return Ok(conn),
+            Err(e) if !is_transient_connect_error(&e) => return Err(MigrateError::Connection(e)),
+            Err(e) => e,
+        };
+
+        let remaining = timeout.saturating_sub(started.elapsed());
+        if remaining.is_zero() {
+            return Err(MigrateError::Connection(e));
+        }
+        retry += 1;
+        let delay = backoff.delay(retry).min(remaining);
+        println!(
+            "Waiting for the database ({:.1} s left, retrying in {} ms): {}",
+            remaining.as_secs_f64(),
+            delay.as_millis(),
+            e
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
+
+fn is_transient_connect_error(e: &sqlx::Error) -> bool {
+    match e {
+        sqlx::Error::Configuration(_) | sqlx::Error::Tls(_) => false,
+        sqlx::Error::Database(e) => {
+            // 28000: invalid_authorization_specification, 28P01: invalid_password
+            !matches!(e.code().as_deref(), Some("28000") | Some("28P01"))
+        }
+        _ => true,
+    }
 }
 
-pub async fn read_and_run(path: String) -> Result<(), Box<dyn Error>> {
-    let pool = db_pool().await;
+/// Where a connection goes and whether the connection is encrypted, as
+/// reported by the server.
+#[derive(Debug)]
+pub struct ConnectionInfo {
+    /// `host:port`, or the path of the Unix socket
+    pub address: String,
+    pub database: String,
+    pub user: String,
+    pub server_version: String,
+    /// `sslmode` the connection was made with
+    pub ssl_mode: PgSslMode,
+    /// Negotiated TLS version, or `None` if the connection is not encrypted
+    pub tls_version: Option<String>,
+    pub tls_cipher: Option<String>,
+}
 
+impl ConnectionInfo {
+    /// e.g. "TLSv1.3 (TLS_AES_256_GCM_SHA384)"
+    pub fn tls_label(&self) -> String {
+        match (&self.tls_version, &self.tls_cipher) {
+            (Some(version), Some(cipher)) => format!("{} ({})", version, cipher),
+            (Some(version), None) => version.clone(),
+            (None, _) => "not encrypted".to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ConnectionInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} as {} on {} (PostgreSQL {}, TLS: {})",
+            self.database,
+            self.user,
+            self.address,
+            self.server_version,
+            self.tls_label()
+        )
+    }
+}
+
+/// Describe `conn`, which was opened with `options`.
+pub async fn connection_info(
+    conn: &mut PgConnection,
+    options: &PgConnectOptions,
+) -> Result<ConnectionInfo, MigrateError> {
+    let address = match options.get_socket() {
+        Some(dir) => format!("{}/.s.PGSQL.{}", dir.display(), options.get_port()),
+        None if options.get_host().starts_with('/') => {
+            format!("{}/.s.PGSQL.{}", options.get_host(), options.get_port())
+        }
+        None => format!("{}:{}", options.get_host(), options.get_port()),
+    };
+
+    let row = sqlx::query(
+        "SELECT current_database()::text AS database, current_user::text AS user,
+            current_setting('server_version') AS server_version,
+            s.version AS tls_version, s.cipher AS tls_cipher
+        FROM pg_stat_ssl AS s WHERE s.pid = pg_backend_pid()",
+    )
+    .fetch_one(conn)
+    .await?;
+
+    Ok(ConnectionInfo {
+        address,
+        database: row.get("database"),
+        user: row.get("user"),
+        server_version: row.get("server_version"),
+        ssl_mode: options.get_ssl_mode(),
+        tls_version: row.get("tls_version"),
+        tls_cipher: row.get("tls_cipher"),
+    })
+}
+
+pub async fn read_and_run(
+    conn: &mut PgConnection,
+    path: &str,
+    timeouts: &Timeouts,
+    retry: &RetryPolicy,
+) -> Result<(), MigrateError> {
     // Read SQL queries
-    let queries = parse_sql_file(&path).unwrap();
+    let contents = read_sql_file(path)?;
 
-    execute_queries(&pool, queries)
-        .await
-        .expect("Query execute failed");
-    Ok(())
+    execute_sql(conn, path, &contents, timeouts, retry).await
 }
 
-fn parse_sql_file(path: &str) -> Result<Vec<String>, Box<dyn Error>> {
-    let contents = fs::read_to_string(path)?;
+/// Execute the statements in `sql`, in one transaction unless the file
+/// starts with `-- migrate:no-transaction`.
+///
+/// Only transactional files are retried, since a file without a transaction
+/// may have committed some of its statements before failing.
+pub(crate) async fn execute_sql(
+    conn: &mut PgConnection,
+    file: &str,
+    sql: &str,
+    timeouts: &Timeouts,
+    retry: &RetryPolicy,
+) -> Result<(), MigrateError> {
+    let queries = parse_text(sql);
+    let directives = parse_directives(sql);
+    let timeouts = timeouts.with_directives(&directives);
+
+    if directives.no_transaction {
+        execute_statements_without_transaction(conn, file, &queries, &timeouts).await
+    } else {
+        execute_queries(conn, file, queries, &timeouts, retry).await
+    }
+}
+
+/// `lock_timeout` and `statement_timeout` for the statements in a file, as
+/// PostgreSQL durations such as `5s` or `500ms`. `None` keeps the setting of
+/// the role or server.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Timeouts {
+    pub lock_timeout: Option<String>,
+    pub statement_timeout: Option<String>,
+}
+
+impl Timeouts {
+    /// These timeouts, overridden by any set in the file's header.
+    pub fn with_directives(&self, directives: &Directives) -> Timeouts {
+        Timeouts {
+            lock_timeout: directives
+                .lock_timeout
+                .clone()
+                .or_else(|| self.lock_timeout.clone()),
+            statement_timeout: directives
+                .statement_timeout
+                .clone()
+                .or_else(|| self.statement_timeout.clone()),
+        }
+    }
+}
+
+/// Apply `timeouts` with `SET LOCAL`, so they end with the transaction.
+///
+/// Unset timeouts are put back to the session default, so a migration does
+/// not inherit the timeouts of the one before it in an atomic run.
+pub(crate) async fn set_local_timeouts(
+    conn: &mut PgConnection,
+    file: &str,
+    timeouts: &Timeouts,
+) -> Result<(), MigrateError> {
+    set_timeouts(conn, file, timeouts, "SET LOCAL").await
+}
 
-    let queries = parse_text(&contents);
+async fn set_timeouts(
+    conn: &mut PgConnection,
+    file: &str,
+    timeouts: &Timeouts,
+    command: &str,
+) -> Result<(), MigrateError> {
+    let settings = [
+        ("lock_timeout", &timeouts.lock_timeout),
+        ("statement_timeout", &timeouts.statement_timeout),
+    ];
+    for (name, value) in settings {
+        let value = match value {
+            Some(value) => format!("'{}'", value.replace('\'', "''")),
+            None => "DEFAULT".to_string(),
+        };
+        let query = format!("{} {} TO {}", command, name, value);
+        sqlx::query(&query).execute(&mut *conn).await.map_err(|e| {
+            MigrateError::Config(format!("Cannot set {} for {}: {}", name, file, e))
+        })?;
+    }
 
-    Ok(queries)
+    Ok(())
 }
 
 async fn execute_select_query(
-    db: &Pool<Postgres>,
+    conn: &mut PgConnection,
     query: String,
-) -> Result<Vec<PgRow>, Box<dyn Error>> {
+) -> Result<Vec<PgRow>, MigrateError> {
     // Check if the query string starts with SELECT
     if !query.trim_start().to_uppercase().starts_with("SELECT") {
-        return Err("Query must start with SELECT".into());
+        return Err(MigrateError::Invalid(
+            "Query must start with SELECT".to_string(),
+        ));
     }
 
-    let result = sqlx::query(&query).fetch_all(db).await;
+    // Not prepared and cached: the session outlives upgrades of the migration
+    // table, after which a cached `SELECT *` plan would be rejected
+    let rows = sqlx::query(&query)
+        .persistent(false)
+        .fetch_all(conn)
+        .await?;
 
-    match result {
-        Ok(rows) => Ok(rows),
-        Err(e) => {
-            println!("Database query failed: {}", e);
-            Err(e.into())
-        }
-    }
+    Ok(rows)
 }
 
-async fn execute_query(db: &Pool<Postgres>, query: String) {
+async fn execute_query(conn: &mut PgConnection, query: String) -> Result<(), MigrateError> {
     // Gererate transaction
-    let mut tx = db.begin().await.expect("transaction error.");
+    let mut tx = conn.begin().await?;
 
     let result = sqlx::query(&query).execute(&mut *tx).await;
 
     match result {
         Ok(_) => {}
         Err(e) => {
-            println!("Database query failed: {}", e);
             // Rollback
-            tx.rollback().await.expect("Transaction rollback error.");
-            return;
+            tx.rollback().await?;
+            return Err(e.into());
         }
     }
 
     // Transaction commit
-    let _ = tx.commit().await.unwrap_or_else(|e| {
-        println!("{:?}", e);
-    });
+    tx.commit().await?;
+    Ok(())
 }
 
-async fn execute_queries(db: &Pool<Postgres>, queries: Vec<String>) -> Result<(), Box<dyn Error>> {
+/// Execute `queries` in one transaction, rolling back and trying again
+/// under `retry` if it fails with a lock timeout, serialization failure or
+/// deadlock.
+pub(crate) async fn execute_queries(
+    conn: &mut PgConnection,
+    file: &str,
+    queries: Vec<String>,
+    timeouts: &Timeouts,
+    retry: &RetryPolicy,
+) -> Result<(), MigrateError> {
+    let mut retries = retry.retries(file);
+    loop {
+        match execute_queries_once(conn, file, &queries, timeouts).await {
+            Err(e) if retries.should_retry(&e).await => {}
+            result => return result,
+        }
+    }
+}
+
+async fn execute_queries_once(
+    conn: &mut PgConnection,
+    file: &str,
+    queries: &[String],
+    timeouts: &Timeouts,
+) -> Result<(), MigrateError> {
+    let transaction_error = |source| MigrateError::Transaction {
+        file: file.to_string(),
+        source,
+    };
+
     // Gererate transaction
-    let mut tx = db.begin().await.expect("transaction error.");
+    let mut tx = conn.begin().await.map_err(transaction_error)?;
 
-    for query in queries {
-        // Execute SQL query
-        let result = sqlx::query(&query).execute(&mut *tx).await;
-
-        match result {
-            Ok(_) => {}
-            Err(e) => {
-                println!("Database query failed: {}", e);
-                // Rollback
-                tx.rollback().await.expect("Transaction rollback error.");
-                return Err(e.into());
-            }
-        }
+    let result = match set_local_timeouts(&mut tx, file, timeouts).await {
+        Ok(()) => execute_statements(&mut tx, file, queries).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        // Rollback
+        tx.rollback().await.map_err(transaction_error)?;
+        return Err(e);
     }
 
     // Transaction commit
-    let _ = tx.commit().await.unwrap_or_else(|e| {
-        println!("{:?}", e);
-    });
+    tx.commit().await.map_err(transaction_error)?;
 
     Ok(())
 }
 
-pub async fn get_executable_query_count(n: u64) -> u64 {
-    let pool = db_pool().await;
-    let query = "SELECT COUNT(*) FROM _migrations".to_string();
-    let count: u64 = get_count(&pool, query).await.expect("Not found data") as u64;
+/// Execute statements in autocommit mode with `timeouts` set for the session,
+/// then reset them so they do not carry over to what runs next on `conn`.
+///
+/// Statements before a failing one stay applied, since there is no
+/// transaction to roll back.
+pub(crate) async fn execute_statements_without_transaction(
+    conn: &mut PgConnection,
+    file: &str,
+    queries: &[String],
+    timeouts: &Timeouts,
+) -> Result<(), MigrateError> {
+    set_timeouts(conn, file, timeouts, "SET").await?;
+    let result = execute_statements(conn, file, queries).await;
+    let reset = set_timeouts(conn, file, &Timeouts::default(), "SET").await;
+
+    result.and(reset)
+}
 
-    if n > count {
-        count
-    } else {
-        n
+/// Execute statements on `conn`, reporting the first failure by its 1-based
+/// position in `file`. Statements cancelled by `lock_timeout` or
+/// `statement_timeout` are reported as timeouts.
+pub(crate) async fn execute_statements(
+    conn: &mut PgConnection,
+    file: &str,
+    queries: &[String],
+) -> Result<(), MigrateError> {
+    for (index, query) in queries.iter().enumerate() {
+        // Execute SQL query
+        sqlx::query(query)
+            .execute(&mut *conn)
+            .await
+            .map_err(|e| statement_error(file, index + 1, e))?;
     }
+
+    Ok(())
 }
 
-async fn get_count(db: &Pool<Postgres>, query: String) -> Result<i64, Box<dyn Error>> {
-    let rows = execute_select_query(&db, query).await?;
-    if let Some(row) = rows.first() {
-        let count: i64 = row.get(0);
-        Ok(count)
+fn statement_error(file: &str, statement: usize, source: sqlx::Error) -> MigrateError {
+    let code = source.as_database_error().and_then(|e| e.code());
+    // 55P03: lock_not_available, 57014: query_canceled
+    if matches!(code.as_deref(), Some("55P03") | Some("57014")) {
+        MigrateError::Timeout {
+            file: file.to_string(),
+            statement,
+            source,
+        }
     } else {
-        Err("No count result found".into())
+        MigrateError::Execution {
+            file: file.to_string(),
+            statement,
+            source,
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use tokio;
+    use crate::file::migration_version;
+
+    #[test]
+    fn test_qualified_table_name() {
+        assert_eq!(qualified_table_name(None, "_migrations"), "\"_migrations\"");
+        assert_eq!(
+            qualified_table_name(Some("App"), "my \"table\""),
+            "\"App\".\"my \"\"table\"\"\""
+        );
+    }
 
     #[tokio::test]
-    async fn test_get_executable_query_count() {
-        let result = get_executable_query_count(100).await;
-        assert_eq!(result, 2);
+    async fn test_connect_timeout() {
+        // Nothing listens on port 1, so the connection is retried until the timeout
+        let options = connect_options(Some("postgres://postgres@127.0.0.1:1/db")).unwrap();
+        let started = Instant::now();
+        let result = connect(&options, Duration::from_secs(1)).await;
+        assert!(matches!(result, Err(MigrateError::Connection(_))));
+        assert!(started.elapsed() >= Duration::from_secs(1));
+
+        // An unknown role is not retried
+        let options = env_connect_options(None)
+            .unwrap()
+            .username("_migrate_no_such_role");
+        let started = Instant::now();
+        let result = connect(&options, Duration::from_secs(5)).await;
+        assert!(matches!(result, Err(MigrateError::Connection(_))));
+        assert!(started.elapsed() < Duration::from_secs(1));
     }
 
     #[tokio::test]
-    async fn test_get_count() {
-        let pool = db_pool().await;
-        let query = "SELECT COUNT(*) FROM _migrations".to_string();
-        let count = get_count(&pool, query).await;
-        assert!(count.is_ok());
+    async fn test_connection_info() {
+        let mut conn = db_connection().await.unwrap();
+        let options = env_connect_options(None).unwrap();
+        let info = connection_info(&mut conn, &options).await.unwrap();
+
+        assert!(!info.server_version.is_empty());
+        assert_eq!(
+            info.tls_version.is_some(),
+            info.tls_label() != "not encrypted"
+        );
+        assert!(info.to_string().contains(&info.address));
     }
 
     #[tokio::test]
     async fn test_remove_migration() {
-        let pool = db_pool().await;
+        let mut conn = db_connection().await.unwrap();
         let down_file = "2024-04-06_1712403500_down.sql".to_string();
-        let _ = remove_migration(&pool, down_file).await;
+        let _ = remove_migration(&mut conn, "_migrations", down_file).await;
     }
 
     #[tokio::test]
-    async fn test_migrate() {
-        let _ = migrate().await;
+    async fn test_get_last_migration() {
+        let mut conn = db_connection().await.unwrap();
+        let up_file = get_last_migration(&mut conn, "_migrations", Migrations::UP)
+            .await
+            .unwrap();
+        let down_file = get_last_migration(&mut conn, "_migrations", Migrations::DOWN)
+            .await
+            .unwrap();
+        assert_eq!(
+            up_file.as_deref().map(migration_version),
+            down_file.as_deref().map(migration_version)
+        );
+
+        // A missing table is an error, not "nothing applied yet"
+        let result = get_last_migration(&mut conn, "_no_such_migrations", Migrations::UP).await;
+        assert!(result.is_err());
     }
 
     #[tokio::test]
-    async fn test_get_last_migration() {
-        let pool = db_pool().await;
-        let result = get_last_migration(&pool, Migrations::UP).await;
-        match result {
-            Some(value) => println!("Got a value: {}", value),
-            None => println!("Got nothing"),
-        }
+    async fn test_insert_migration() {
+        let mut conn = db_connection().await.unwrap();
+        let migration = NewMigration {
+            version: "2024-04-06_1712403500".to_string(),
+            up_file: "2024-04-06_1712403500_up.sql".to_string(),
+            down_file: "2024-04-06_1712403500_down.sql".to_string(),
+            up_checksum: "up_checksum".to_string(),
+            down_checksum: "down_checksum".to_string(),
+            transactional: true,
+            duration_ms: 0,
+            batch: 1,
+        };
+        let _ = insert_migration(&mut conn, "_migrations", migration).await;
+    }
 
-        let result = get_last_migration(&pool, Migrations::DOWN).await;
-        match result {
-            Some(value) => println!("Got a value: {}", value),
-            None => println!("Got nothing"),
-        }
+    #[tokio::test]
+    async fn test_execute_sql_timeout() {
+        let mut conn = db_connection().await.unwrap();
+        let timeouts = Timeouts {
+            lock_timeout: None,
+            statement_timeout: Some("2s".to_string()),
+        };
+
+        let sql = "-- migrate:statement-timeout=10ms\nSELECT 1;\nSELECT pg_sleep(1);";
+        let err = execute_sql(
+            &mut conn,
+            "timeout_up.sql",
+            sql,
+            &timeouts,
+            &RetryPolicy::default(),
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, MigrateError::Timeout { statement: 2, .. }));
+
+        let sql = "SELECT pg_sleep(0.1);";
+        execute_sql(
+            &mut conn,
+            "timeout_up.sql",
+            sql,
+            &timeouts,
+            &RetryPolicy::default(),
+        )
+        .await
+        .unwrap();
     }
 
     #[tokio::test]
-    async fn test_insert_migration() {
-        let pool = db_pool().await;
-        let up_file = "2024-04-06_1712403500_up.sql".to_string();
-        let down_file = "2024-04-06_1712403500_down.sql".to_string();
-        let _ = insert_migration(&pool, up_file, down_file).await;
+    async fn test_execute_sql_retry() {
+        let mut conn = db_connection().await.unwrap();
+        let setup = "CREATE TABLE IF NOT EXISTS _test_retry (id INT)";
+        sqlx::query(setup).execute(&mut conn).await.unwrap();
+
+        // Hold a conflicting lock for longer than the lock timeout, but not
+        // for longer than the retries
+        let mut holder = db_connection().await.unwrap();
+        sqlx::query("BEGIN").execute(&mut holder).await.unwrap();
+        sqlx::query("LOCK TABLE _test_retry IN ACCESS EXCLUSIVE MODE")
+            .execute(&mut holder)
+            .await
+            .unwrap();
+        let release = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            sqlx::query("ROLLBACK").execute(&mut holder).await.unwrap();
+        });
+
+        let timeouts = Timeouts {
+            lock_timeout: Some("50ms".to_string()),
+            statement_timeout: None,
+        };
+        let retry = RetryPolicy {
+            max_retries: 10,
+            base_delay: Duration::from_millis(50),
+            max_delay: Duration::from_millis(200),
+        };
+        let sql = "INSERT INTO _test_retry VALUES (1);";
+        execute_sql(&mut conn, "retry_up.sql", sql, &timeouts, &retry)
+            .await
+            .unwrap();
+        release.await.unwrap();
+
+        sqlx::query("DROP TABLE _test_retry")
+            .execute(&mut conn)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_get_applied_migrations() {
+        let mut conn = db_connection().await.unwrap();
+        let table = "_test_applied_migrations";
+        let setup = [
+            format!("DROP TABLE IF EXISTS {}", table),
+            format!(
+                "CREATE TABLE {} (id SERIAL, up_file TEXT, down_file TEXT, batch TEXT)",
+                table
+            ),
+            format!(
+                "INSERT INTO {} (up_file, down_file, batch) VALUES ('a_up.sql', 'a_down.sql', '1')",
+                table
+            ),
+        ];
+        for query in setup {
+            sqlx::query(&query).execute(&mut conn).await.unwrap();
+        }
+
+        // Columns the table predates are left out, but a wrong type is an error
+        let result = get_applied_migrations(&mut conn, table).await;
+        assert!(matches!(result, Err(MigrateError::Bookkeeping(_))));
+
+        let query = format!("ALTER TABLE {} DROP COLUMN batch", table);
+        sqlx::query(&query).execute(&mut conn).await.unwrap();
+        let applied = get_applied_migrations(&mut conn, table).await.unwrap();
+        assert_eq!(applied[0].up_file, "a_up.sql");
+        assert!(applied[0].transactional);
+        assert_eq!(applied[0].batch, None);
+
+        let query = format!("DROP TABLE {}", table);
+        sqlx::query(&query).execute(&mut conn).await.unwrap();
     }
 
     #[tokio::test]
     async fn test_select_query() {
-        let pool = db_pool().await;
+        let mut conn = db_connection().await.unwrap();
         let query = "SELECT up_file FROM _migrations ORDER BY id DESC LIMIT 1".to_string();
-        let result = execute_select_query(&pool, query).await;
+        let result = execute_select_query(&mut conn, query).await;
         for row in result.unwrap() {
             let filename: String = row.get("up_file");
             println!("{:?}", filename);
         }
         let query = "DELETE FROM test1".to_string();
-        let result = execute_select_query(&pool, query).await;
+        let result = execute_select_query(&mut conn, query).await;
         assert!(result.is_err(), "Expected an error for non-SELECT query");
     }
 }
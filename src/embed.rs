@@ -0,0 +1,106 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+
+use crate::error::MigrateError;
+use crate::file::{create_file, get_all_migrations};
+
+/// Name of the file `embed_migrations` writes into `OUT_DIR`.
+pub const EMBEDDED_MIGRATIONS_FILE: &str = "embedded_migrations.rs";
+
+/// An up/down pair compiled into the binary.
+#[derive(Debug)]
+pub struct EmbeddedMigration {
+    pub version: &'static str,
+    pub up_file: &'static str,
+    pub down_file: &'static str,
+    pub up_sql: &'static str,
+    pub down_sql: &'static str,
+}
+
+/// Embed the migrations in `dir` so `embedded_migrations!` can include them.
+///
+/// Call this from a build script. Files are paired by the same rules as
+/// `file::get_all_migrations`, so unpaired files fail the build.
+///
+/// ```ignore
+/// // build.rs
+/// fn main() {
+///     migrate::embed::embed_migrations("./Migrations").unwrap();
+/// }
+/// ```
+pub fn embed_migrations(dir: &str) -> Result<(), MigrateError> {
+    let out_dir = env::var("OUT_DIR").map_err(|_| {
+        MigrateError::Config("OUT_DIR must be set; call embed_migrations from build.rs".to_string())
+    })?;
+
+    println!("cargo:rerun-if-changed={}", dir);
+    let code = generate_embedded_migrations(dir)?;
+    let out_file = Path::new(&out_dir).join(EMBEDDED_MIGRATIONS_FILE);
+
+    create_file(&out_file.to_string_lossy(), &code)
+}
+
+/// Rust source for an array of `EmbeddedMigration`s built from `dir`.
+fn generate_embedded_migrations(dir: &str) -> Result<String, MigrateError> {
+    let migrations = get_all_migrations(dir)?;
+    // include_str! resolves relative paths against the generated file in OUT_DIR
+    let dir = fs::canonicalize(dir).map_err(|e| MigrateError::io(dir, e))?;
+
+    let mut code = String::from("[\n");
+    for m in migrations {
+        code.push_str(&format!(
+            "    EmbeddedMigration {{
+        version: {:?},
+        up_file: {:?},
+        down_file: {:?},
+        up_sql: include_str!({:?}),
+        down_sql: include_str!({:?}),
+    }},\n",
+            m.version,
+            m.up_file,
+            m.down_file,
+            dir.join(&m.up_file),
+            dir.join(&m.down_file),
+        ));
+    }
+    code.push(']');
+
+    Ok(code)
+}
+
+/// Migrations written by `embed::embed_migrations` in the build script.
+///
+/// ```ignore
+/// static MIGRATIONS: &[EmbeddedMigration] = migrate::embedded_migrations!();
+///
+/// Migrator::new().embedded(MIGRATIONS).run(None).await?;
+/// ```
+#[macro_export]
+macro_rules! embedded_migrations {
+    () => {{
+        #[allow(unused_imports)]
+        use $crate::embed::EmbeddedMigration;
+        &include!(concat!(env!("OUT_DIR"), "/embedded_migrations.rs"))
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate_embedded_migrations() {
+        let code = generate_embedded_migrations("./test").unwrap();
+        let dir = fs::canonicalize("./test").unwrap();
+
+        assert!(code.starts_with('['));
+        assert!(code.ends_with(']'));
+        assert_eq!(code.matches("EmbeddedMigration {").count(), 3);
+        assert!(code.contains("version: \"test1\""));
+        assert!(code.contains(&format!(
+            "up_sql: include_str!({:?})",
+            dir.join("test1_up.sql")
+        )));
+    }
+}
@@ -0,0 +1,115 @@
+use std::error::Error;
+use std::{fmt, io};
+
+#[derive(Debug)]
+pub enum MigrateError {
+    /// Missing or invalid settings, such as DATABASE_URL
+    Config(String),
+    /// The database could not be reached
+    Connection(sqlx::Error),
+    /// A file or directory could not be read or written
+    Io { path: String, source: io::Error },
+    /// A migration file could not be turned into statements
+    Parse { file: String, message: String },
+    /// Up or down files without a counterpart of the same version
+    UnpairedFiles(Vec<String>),
+    /// Applied migration files whose contents no longer match their checksum
+    ModifiedFiles(Vec<String>),
+    /// A statement in a migration file failed; `statement` is 1-based
+    Execution {
+        file: String,
+        statement: usize,
+        source: sqlx::Error,
+    },
+    /// A statement in a migration file was cancelled by `lock_timeout` or
+    /// `statement_timeout`; `statement` is 1-based
+    Timeout {
+        file: String,
+        statement: usize,
+        source: sqlx::Error,
+    },
+    /// Beginning or committing the transaction for a file failed
+    Transaction { file: String, source: sqlx::Error },
+    /// Reading or writing the migration table failed
+    Bookkeeping(sqlx::Error),
+    /// A migration recorded in the migration table has no file
+    MissingFile(String),
+    /// The migration lock could not be taken
+    Lock(String),
+    /// The requested operation does not apply to the current state
+    Invalid(String),
+}
+
+impl MigrateError {
+    pub fn io(path: &str, source: io::Error) -> Self {
+        MigrateError::Io {
+            path: path.to_string(),
+            source,
+        }
+    }
+}
+
+impl fmt::Display for MigrateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MigrateError::Config(message) => write!(f, "{}", message),
+            MigrateError::Connection(e) => write!(f, "Cannot connect to the database: {}", e),
+            MigrateError::Io { path, source } => write!(f, "Failed to access {}: {}", path, source),
+            MigrateError::Parse { file, message } => {
+                write!(f, "Failed to parse {}: {}", file, message)
+            }
+            MigrateError::UnpairedFiles(files) => {
+                write!(f, "Unpaired migration files: {}", files.join(", "))
+            }
+            MigrateError::ModifiedFiles(files) => {
+                write!(
+                    f,
+                    "Applied migrations have been modified: {}",
+                    files.join(", ")
+                )
+            }
+            MigrateError::Execution {
+                file,
+                statement,
+                source,
+            } => write!(f, "Statement {} in {} failed: {}", statement, file, source),
+            MigrateError::Timeout {
+                file,
+                statement,
+                source,
+            } => write!(
+                f,
+                "Statement {} in {} timed out: {}",
+                statement, file, source
+            ),
+            MigrateError::Transaction { file, source } => {
+                write!(f, "Transaction for {} failed: {}", file, source)
+            }
+            MigrateError::Bookkeeping(e) => write!(f, "Migration table query failed: {}", e),
+            MigrateError::MissingFile(file) => {
+                write!(f, "Could not find the last migration ran: {}", file)
+            }
+            MigrateError::Lock(message) => write!(f, "{}", message),
+            MigrateError::Invalid(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl Error for MigrateError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            MigrateError::Connection(e) | MigrateError::Bookkeeping(e) => Some(e),
+            MigrateError::Io { source, .. } => Some(source),
+            MigrateError::Execution { source, .. }
+            | MigrateError::Timeout { source, .. }
+            | MigrateError::Transaction { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<sqlx::Error> for MigrateError {
+    fn from(e: sqlx::Error) -> Self {
+        MigrateError::Bookkeeping(e)
+    }
+}
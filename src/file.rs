@@ -1,70 +1,104 @@
-use std::error::Error;
 use std::fs;
 use std::fs::File;
 use std::io::Read;
 use std::io::{self, Write};
 use std::path::Path;
 
-use crate::time_util::{jp_date, unix_time_stamp};
+use chrono::format::{Item, StrftimeItems};
+use chrono::Local;
+use sha2::{Digest, Sha256};
+
+use crate::error::MigrateError;
 use crate::Migrations;
 
-pub fn create_file(filepath: &str, contents: &str) -> io::Result<()> {
-    let mut file = File::create(filepath)?;
-    file.write_all(contents.as_bytes())?;
-    Ok(())
+/// Default `naming` format: the local date and the Unix time, e.g.
+/// `2024-04-06_1712403500`.
+pub const DEFAULT_NAMING: &str = "%Y-%m-%d_%s";
+
+pub fn create_file(filepath: &str, contents: &str) -> Result<(), MigrateError> {
+    let write = || -> io::Result<()> {
+        let mut file = File::create(filepath)?;
+        file.write_all(contents.as_bytes())
+    };
+    write().map_err(|e| MigrateError::io(filepath, e))
 }
 
-pub fn read_file(filepath: &str) -> io::Result<String> {
-    let mut file = File::open(filepath)?;
-    let mut content = String::new();
-    file.read_to_string(&mut content)?;
-    Ok(content)
+pub fn read_file(filepath: &str) -> Result<String, MigrateError> {
+    let read = || -> io::Result<String> {
+        let mut file = File::open(filepath)?;
+        let mut content = String::new();
+        file.read_to_string(&mut content)?;
+        Ok(content)
+    };
+    read().map_err(|e| MigrateError::io(filepath, e))
 }
 
-pub fn clean_up_file(path: &str) -> Result<(), Box<dyn Error>> {
-    fs::remove_file(path)?;
-    Ok(())
+pub fn clean_up_file(path: &str) -> Result<(), MigrateError> {
+    fs::remove_file(path).map_err(|e| MigrateError::io(path, e))
 }
 
-pub fn create_migration_file() -> Result<(), Box<dyn Error>> {
+/// Create an empty up and down file in `dir`, versioned with the current
+/// time in the chrono `strftime` format `naming`.
+pub fn create_migration_file(dir: &str, naming: &str) -> Result<(), MigrateError> {
+    let version = new_version(naming)?;
+
     // Create Migrations directory if it does not exist
-    let dir_path = Path::new("./Migrations");
+    let dir_path = Path::new(dir);
     if !dir_path.exists() {
-        fs::create_dir_all(dir_path).expect("Failed create directory");
+        fs::create_dir_all(dir_path).map_err(|e| MigrateError::io(dir, e))?;
     }
 
-    // Retrieve common timestamp
-    let jp_time = jp_date();
-    let unix_time = unix_time_stamp();
-
     // create empty sql up file
-    let filepath_up = format!("./Migrations/{}_{}_up.sql", &jp_time, &unix_time);
+    let filepath_up = format!("{}/{}_up.sql", dir, &version);
     if Path::new(&filepath_up).exists() {
         println!("File already exists: {}", filepath_up);
     } else if let Err(e) = create_file(&filepath_up, "") {
         let _ = clean_up_file(&filepath_up);
-        return Err(e.into());
+        return Err(e);
     }
 
     // create empty sql down file
-    let filepath_down = format!("./Migrations/{}_{}_down.sql", &jp_time, &unix_time);
+    let filepath_down = format!("{}/{}_down.sql", dir, &version);
     if Path::new(&filepath_down).exists() {
         println!("File already exists: {}", filepath_down);
     } else if let Err(e) = create_file(&filepath_down, "") {
         let _ = clean_up_file(&filepath_up);
         let _ = clean_up_file(&filepath_down);
-        return Err(e.into());
+        return Err(e);
     }
 
     Ok(())
 }
 
-pub fn get_all_migration_files(dir: &str, migration_type: Migrations) -> io::Result<Vec<String>> {
+/// Version for a migration created now, formatted with `naming`.
+///
+/// Files are applied in the order of their names, so the format should sort
+/// the way the migrations were created.
+pub fn new_version(naming: &str) -> Result<String, MigrateError> {
+    let invalid = || MigrateError::Config(format!("Invalid naming format: {}", naming));
+    let items: Vec<Item> = StrftimeItems::new(naming).collect();
+    if items.iter().any(|item| matches!(item, Item::Error)) {
+        return Err(invalid());
+    }
+
+    let version = Local::now()
+        .format_with_items(items.into_iter())
+        .to_string();
+    if version.is_empty() || version.contains(['/', '\\']) {
+        return Err(invalid());
+    }
+    Ok(version)
+}
+
+pub fn get_all_migration_files(
+    dir: &str,
+    migration_type: Migrations,
+) -> Result<Vec<String>, MigrateError> {
     let mut filenames = vec![];
 
-    let entries = fs::read_dir(dir)?;
+    let entries = fs::read_dir(dir).map_err(|e| MigrateError::io(dir, e))?;
     for entry in entries {
-        let entry = entry?;
+        let entry = entry.map_err(|e| MigrateError::io(dir, e))?;
         let path = entry.path();
 
         if path.is_file() {
@@ -89,6 +123,86 @@ pub fn get_all_migration_files(dir: &str, migration_type: Migrations) -> io::Res
     Ok(filenames)
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub struct Migration {
+    pub version: String,
+    pub up_file: String,
+    pub down_file: String,
+}
+
+/// Pair every up file with the down file of the same version.
+pub fn get_all_migrations(dir: &str) -> Result<Vec<Migration>, MigrateError> {
+    let up_files = get_all_migration_files(dir, Migrations::UP)?;
+    let down_files = get_all_migration_files(dir, Migrations::DOWN)?;
+
+    pair_migration_files(up_files, down_files)
+}
+
+fn pair_migration_files(
+    up_files: Vec<String>,
+    mut down_files: Vec<String>,
+) -> Result<Vec<Migration>, MigrateError> {
+    let mut migrations = vec![];
+    let mut unpaired = vec![];
+
+    for up_file in up_files {
+        let version = migration_version(&up_file).to_string();
+        match down_files
+            .iter()
+            .position(|d| migration_version(d) == version)
+        {
+            Some(index) => {
+                let down_file = down_files.remove(index);
+                migrations.push(Migration {
+                    version,
+                    up_file,
+                    down_file,
+                });
+            }
+            None => unpaired.push(up_file),
+        }
+    }
+    unpaired.append(&mut down_files);
+
+    if !unpaired.is_empty() {
+        unpaired.sort();
+        return Err(MigrateError::UnpairedFiles(unpaired));
+    }
+
+    Ok(migrations)
+}
+
+/// Read a migration file, reporting invalid UTF-8 as a parse error.
+pub fn read_sql_file(filepath: &str) -> Result<String, MigrateError> {
+    fs::read_to_string(filepath).map_err(|e| match e.kind() {
+        io::ErrorKind::InvalidData => MigrateError::Parse {
+            file: filepath.to_string(),
+            message: "file is not valid UTF-8".to_string(),
+        },
+        _ => MigrateError::io(filepath, e),
+    })
+}
+
+/// SHA-256 of the file contents as a lowercase hex string.
+pub fn file_checksum(filepath: &str) -> Result<String, MigrateError> {
+    let contents = fs::read(filepath).map_err(|e| MigrateError::io(filepath, e))?;
+    Ok(checksum(&contents))
+}
+
+/// SHA-256 of `contents` as a lowercase hex string.
+pub fn checksum(contents: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(contents))
+}
+
+/// Version part of a migration file name, e.g. `2024-04-06_1712403500` for
+/// `2024-04-06_1712403500_up.sql`.
+pub fn migration_version(filename: &str) -> &str {
+    filename
+        .strip_suffix("_up.sql")
+        .or_else(|| filename.strip_suffix("_down.sql"))
+        .unwrap_or(filename)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -110,16 +224,87 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_get_all_migrations() {
+        let migrations = get_all_migrations("./test").unwrap();
+        let versions: Vec<&str> = migrations.iter().map(|m| m.version.as_str()).collect();
+        assert_eq!(versions, vec!["test1", "test2", "test3"]);
+        assert_eq!(
+            migrations[0],
+            Migration {
+                version: "test1".to_string(),
+                up_file: "test1_up.sql".to_string(),
+                down_file: "test1_down.sql".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_pair_migration_files_unpaired() {
+        let up_files = vec!["a_up.sql".to_string(), "b_up.sql".to_string()];
+        let down_files = vec!["a_down.sql".to_string(), "c_down.sql".to_string()];
+
+        let err = pair_migration_files(up_files, down_files).unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "Unpaired migration files: b_up.sql, c_down.sql"
+        );
+    }
+
+    #[test]
+    fn test_file_checksum() {
+        let filepath = "./test/test_checksum.txt";
+        let _ = create_file(filepath, "SELECT 1;");
+
+        assert_eq!(
+            file_checksum(filepath).unwrap(),
+            "17db4fd369edb9244b9f91d9aeed145c3d04ad8ba6e95d06247f07a63527d11a"
+        );
+
+        let _ = clean_up_file(filepath);
+        assert!(file_checksum(filepath).is_err());
+    }
+
+    #[test]
+    fn test_migration_version() {
+        assert_eq!(
+            migration_version("2024-04-06_1712403500_up.sql"),
+            "2024-04-06_1712403500"
+        );
+        assert_eq!(
+            migration_version("2024-04-06_1712403500_down.sql"),
+            "2024-04-06_1712403500"
+        );
+        assert_eq!(
+            migration_version("2024-04-06_1712403500"),
+            "2024-04-06_1712403500"
+        );
+    }
+
+    #[test]
+    fn test_new_version() {
+        let version = new_version(DEFAULT_NAMING).unwrap();
+        let (date, unix_time) = version.split_once('_').unwrap();
+        assert_eq!(date.len(), "2024-04-06".len());
+        assert!(unix_time.parse::<i64>().is_ok());
+
+        assert_eq!(new_version("%Y").unwrap().len(), 4);
+        assert!(new_version("%Y%Q").is_err());
+        assert!(new_version("%Y/%m").is_err());
+        assert!(new_version("").is_err());
+    }
+
     #[test]
     fn test_clean_up_file() {
         let filepath1 = "./Migrations/test.txt";
-        let _ = create_file(&filepath1, "");
-        assert!(clean_up_file(&filepath1).is_ok());
+        let _ = create_file(filepath1, "");
+        assert!(clean_up_file(filepath1).is_ok());
 
         let filepath2 = "./Migrations/test1.txt";
-        let _ = create_file(&filepath2, "");
-        assert!(clean_up_file(&filepath1).is_err());
-        let _ = clean_up_file(&filepath2);
+        let _ = create_file(filepath2, "");
+        assert!(clean_up_file(filepath1).is_err());
+        let _ = clean_up_file(filepath2);
     }
 
     #[test]
@@ -128,7 +313,7 @@ mod tests {
 
         // cleansing test file.
         if Path::new(filepath).exists() {
-            let _ = fs::remove_file(filepath).expect("File not Exists");
+            fs::remove_file(filepath).expect("File not Exists");
         }
 
         // create test file.
@@ -139,7 +324,7 @@ mod tests {
 
         // cleansing test file.
         if Path::new(filepath).exists() {
-            let _ = fs::remove_file(filepath).expect("File not Exists");
+            fs::remove_file(filepath).expect("File not Exists");
         }
 
         // create empty file.
@@ -150,7 +335,7 @@ mod tests {
 
         // cleansing test file.
         if Path::new(filepath).exists() {
-            let _ = fs::remove_file(filepath).expect("File not Exists");
+            fs::remove_file(filepath).expect("File not Exists");
         }
     }
 
@@ -160,7 +345,7 @@ mod tests {
 
         // cleansing test file.
         if Path::new(filepath).exists() {
-            let _ = fs::remove_file(filepath).expect("File not Exists");
+            fs::remove_file(filepath).expect("File not Exists");
         }
 
         // test result to create file
@@ -172,7 +357,7 @@ mod tests {
 
         // cleansing test file.
         if Path::new(filepath).exists() {
-            let _ = fs::remove_file(filepath).expect("File not Exists");
+            fs::remove_file(filepath).expect("File not Exists");
         }
 
         // test create
@@ -189,7 +374,7 @@ mod tests {
 
         // cleansing test file.
         if Path::new(filepath).exists() {
-            let _ = fs::remove_file(filepath).expect("File not Exists");
+            fs::remove_file(filepath).expect("File not Exists");
         }
     }
 }
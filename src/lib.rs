@@ -1,8 +1,21 @@
+pub mod config;
+pub mod conninfo;
 pub mod console;
 pub mod db;
+pub mod embed;
+pub mod error;
 pub mod file;
+pub mod lock;
+pub mod migrator;
 pub mod parser;
-pub mod time_util;
+pub mod retry;
+pub mod schema;
+pub mod source;
+pub mod status;
+
+pub use embed::EmbeddedMigration;
+pub use error::MigrateError;
+pub use migrator::{Migrator, RollbackTarget};
 
 pub enum Migrations {
     UP,
@@ -0,0 +1,161 @@
+use sha2::{Digest, Sha256};
+use sqlx::postgres::PgConnection;
+use sqlx::Row;
+use std::time::{Duration, Instant};
+
+use crate::db::{qualified_table_name, quote_identifier};
+use crate::error::MigrateError;
+
+const RETRY_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Session-level advisory lock that serializes migration runs.
+///
+/// The lock belongs to the session it was taken on and must be released on
+/// the same connection. If it is not, the server keeps it until the session
+/// ends.
+#[must_use = "the lock is held until released or the session ends"]
+pub struct MigrationLock {
+    key: i64,
+}
+
+/// Advisory lock key derived from the schema-qualified bookkeeping table
+/// name.
+///
+/// The key is kept within 32 bits so it can be matched against `pg_locks.objid`.
+pub fn lock_key(table: &str) -> i64 {
+    let digest = Sha256::digest(table.as_bytes());
+    u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]]) as i64
+}
+
+/// Take the lock for the migration table `table` in `schema`, waiting up to
+/// `timeout` for another run to release it.
+pub async fn acquire_lock(
+    conn: &mut PgConnection,
+    schema: Option<&str>,
+    table: &str,
+    timeout: Duration,
+) -> Result<MigrationLock, MigrateError> {
+    let key = lock_key(&resolved_table_name(conn, schema, table).await?);
+    let started = Instant::now();
+    let mut reported = false;
+
+    loop {
+        let locked: bool = sqlx::query("SELECT pg_try_advisory_lock($1)")
+            .bind(key)
+            .fetch_one(&mut *conn)
+            .await
+            .map_err(lock_error)?
+            .get(0);
+        if locked {
+            return Ok(MigrationLock { key });
+        }
+
+        let holder = match lock_holder(conn, key).await? {
+            Some(pid) => format!("backend PID {}", pid),
+            None => "another session".to_string(),
+        };
+        if started.elapsed() >= timeout {
+            return Err(MigrateError::Lock(format!(
+                "Timed out after {}s waiting for the migration lock held by {}",
+                timeout.as_secs(),
+                holder
+            )));
+        }
+        if !reported {
+            println!("Waiting for the migration lock held by {}", holder);
+            reported = true;
+        }
+        tokio::time::sleep(RETRY_INTERVAL).await;
+    }
+}
+
+/// `table` qualified with `schema`, or else with the schema it resolves to on
+/// `conn`, so that runs naming the same table differently share one lock.
+async fn resolved_table_name(
+    conn: &mut PgConnection,
+    schema: Option<&str>,
+    table: &str,
+) -> Result<String, MigrateError> {
+    if schema.is_some() {
+        return Ok(qualified_table_name(schema, table));
+    }
+
+    // The schema of the existing table, or where CREATE TABLE would put it
+    let query = "SELECT COALESCE(
+            (SELECT n.nspname::text FROM pg_class c
+                JOIN pg_namespace n ON n.oid = c.relnamespace
+                WHERE c.oid = to_regclass($1)),
+            current_schema()::text)";
+    let schema: Option<String> = sqlx::query_scalar(query)
+        .bind(quote_identifier(table))
+        .fetch_one(&mut *conn)
+        .await
+        .map_err(lock_error)?;
+
+    Ok(qualified_table_name(schema.as_deref(), table))
+}
+
+async fn lock_holder(conn: &mut PgConnection, key: i64) -> Result<Option<i32>, MigrateError> {
+    let query = "SELECT pid FROM pg_locks
+        WHERE locktype = 'advisory' AND granted
+        AND classid = 0 AND objid = $1::oid AND objsubid = 1
+        LIMIT 1";
+    let row = sqlx::query(query)
+        .bind(key)
+        .fetch_optional(conn)
+        .await
+        .map_err(lock_error)?;
+
+    Ok(row.map(|r| r.get("pid")))
+}
+
+impl MigrationLock {
+    /// Release the lock on `conn`, the connection it was acquired on.
+    pub async fn release(self, conn: &mut PgConnection) -> Result<(), MigrateError> {
+        sqlx::query("SELECT pg_advisory_unlock($1)")
+            .bind(self.key)
+            .execute(conn)
+            .await
+            .map_err(lock_error)?;
+        Ok(())
+    }
+}
+
+fn lock_error(e: sqlx::Error) -> MigrateError {
+    MigrateError::Lock(format!("Failed to take the migration lock: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::db_connection;
+
+    #[test]
+    fn test_lock_key() {
+        assert_eq!(lock_key("_migrations"), lock_key("_migrations"));
+        assert_ne!(lock_key("_migrations"), lock_key("other_migrations"));
+        assert!((0..=u32::MAX as i64).contains(&lock_key("_migrations")));
+    }
+
+    #[tokio::test]
+    async fn test_acquire_lock() {
+        let mut conn = db_connection().await.unwrap();
+        let mut other = db_connection().await.unwrap();
+        let table = "_test_acquire_lock";
+
+        let lock = acquire_lock(&mut conn, None, table, Duration::from_secs(1))
+            .await
+            .unwrap();
+
+        // The same table, named with its schema
+        let result = acquire_lock(&mut other, Some("public"), table, Duration::from_secs(1)).await;
+        let err = result.err().expect("Expected the second lock to time out");
+        assert!(err.to_string().contains("backend PID"));
+
+        lock.release(&mut conn).await.unwrap();
+        let lock = acquire_lock(&mut other, None, table, Duration::from_secs(1))
+            .await
+            .unwrap();
+        lock.release(&mut other).await.unwrap();
+    }
+}
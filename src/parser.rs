@@ -71,17 +71,94 @@ pub fn parse_text(input: &str) -> Vec<String> {
         }
     }
 
-    if !current_sentence.is_empty() {
+    if !current_sentence.trim().is_empty() {
         sentences.push(current_sentence.trim().to_string());
     }
 
     sentences
 }
 
+/// Settings given by `-- migrate:<name>` comment lines at the top of a file.
+#[derive(Debug, Default, PartialEq)]
+pub struct Directives {
+    /// `-- migrate:no-transaction`: run each statement in autocommit mode
+    pub no_transaction: bool,
+    /// `-- migrate:lock-timeout=<duration>`: `lock_timeout` for this file
+    pub lock_timeout: Option<String>,
+    /// `-- migrate:statement-timeout=<duration>`: `statement_timeout` for
+    /// this file
+    pub statement_timeout: Option<String>,
+}
+
+/// Read the directives in the comment lines before the first statement.
+pub fn parse_directives(input: &str) -> Directives {
+    let mut directives = Directives::default();
+
+    for line in input.lines().map(str::trim) {
+        if line.is_empty() {
+            continue;
+        }
+        let Some(comment) = line.strip_prefix("--") else {
+            break;
+        };
+        let Some(directive) = comment.trim().strip_prefix("migrate:") else {
+            continue;
+        };
+        match directive.split_once('=') {
+            Some((name, value)) if name.trim() == "lock-timeout" => {
+                directives.lock_timeout = Some(value.trim().to_string());
+            }
+            Some((name, value)) if name.trim() == "statement-timeout" => {
+                directives.statement_timeout = Some(value.trim().to_string());
+            }
+            None if directive.trim() == "no-transaction" => {
+                directives.no_transaction = true;
+            }
+            _ => {}
+        }
+    }
+
+    directives
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_parse_directives() {
+        let input = "-- Add an index\n-- migrate:no-transaction\n\nCREATE INDEX CONCURRENTLY a_id ON a (id);";
+        assert_eq!(
+            parse_directives(input),
+            Directives {
+                no_transaction: true,
+                ..Default::default()
+            }
+        );
+
+        let input = "-- migrate:lock-timeout=5s\n-- migrate:statement-timeout = 1min\nALTER TABLE a ADD b INT;";
+        assert_eq!(
+            parse_directives(input),
+            Directives {
+                no_transaction: false,
+                lock_timeout: Some("5s".to_string()),
+                statement_timeout: Some("1min".to_string()),
+            }
+        );
+
+        let input = "CREATE TABLE a (id INT);\n-- migrate:no-transaction\n";
+        assert_eq!(parse_directives(input), Directives::default());
+    }
+
+    #[test]
+    fn test_parse_text_trailing_whitespace() {
+        let input = "CREATE TABLE a (id INT);\nDROP TABLE a;\n\n";
+
+        let sentences = parse_text(input);
+
+        assert_eq!(sentences, vec!["CREATE TABLE a (id INT);", "DROP TABLE a;"]);
+    }
+
     #[test]
     fn test_parse_text() {
         let input = r#"
@@ -108,7 +185,7 @@ mod tests {
 
         let sentences = parse_text(input);
 
-        let exepected = vec![
+        let exepected = [
             r#"CREATE TABLE "User" (
             id BIGSERIAL PRIMARY KEY,
             username VARCHAR(255) NOT NULL,
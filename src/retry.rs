@@ -0,0 +1,230 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+use crate::error::MigrateError;
+
+/// SQLSTATEs worth retrying: lock_not_available, serialization_failure and
+/// deadlock_detected.
+const RETRYABLE_CODES: [&str; 3] = ["55P03", "40001", "40P01"];
+
+/// How a migration that failed with a transient error is retried.
+///
+/// The delay before retry `n` is `base_delay * 2^(n-1)`, capped at
+/// `max_delay`, with a random jitter of up to half of it so concurrent runs
+/// do not retry in lockstep.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// Retries after the first attempt; 0 disables retrying
+    pub max_retries: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 0,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Delay before retry `retry`, counting from 1.
+    pub fn delay(&self, retry: u32) -> Duration {
+        let factor = 2u32.saturating_pow(retry.saturating_sub(1));
+        let delay = self.base_delay.saturating_mul(factor).min(self.max_delay);
+        let jitter = delay.mul_f64(random_fraction() / 2.0);
+
+        delay - jitter
+    }
+
+    /// Retries of `file` under this policy, to be used in a loop that tries
+    /// again while `should_retry` says so:
+    ///
+    /// ```ignore
+    /// let mut retries = policy.retries(file);
+    /// loop {
+    ///     match attempt(conn).await {
+    ///         Err(e) if retries.should_retry(&e).await => {}
+    ///         result => return result,
+    ///     }
+    /// }
+    /// ```
+    ///
+    /// A failed attempt must leave nothing behind, e.g. by rolling back its
+    /// transaction.
+    pub fn retries<'a>(&'a self, file: &'a str) -> Retries<'a> {
+        Retries {
+            policy: self,
+            file,
+            retry: 0,
+        }
+    }
+}
+
+/// Retries used so far on one file.
+pub struct Retries<'a> {
+    policy: &'a RetryPolicy,
+    file: &'a str,
+    retry: u32,
+}
+
+impl Retries<'_> {
+    /// Whether to try again after an attempt failed with `e`. Sleeps for the
+    /// backoff delay before returning true.
+    pub async fn should_retry(&mut self, e: &MigrateError) -> bool {
+        if is_retryable(e) && self.retry < self.policy.max_retries {
+            self.retry += 1;
+            let delay = self.policy.delay(self.retry);
+            println!(
+                "Retrying {} in {} ms ({} of {}): {}",
+                self.file,
+                delay.as_millis(),
+                self.retry,
+                self.policy.max_retries,
+                e
+            );
+            tokio::time::sleep(delay).await;
+            return true;
+        }
+        if self.retry > 0 {
+            println!(
+                "Giving up on {} after {} attempt(s)",
+                self.file,
+                self.retry + 1
+            );
+        }
+        false
+    }
+}
+
+/// Whether `e` was caused by a lock timeout, serialization failure or
+/// deadlock, which may succeed if the transaction is tried again.
+pub fn is_retryable(e: &MigrateError) -> bool {
+    let source = match e {
+        MigrateError::Execution { source, .. }
+        | MigrateError::Timeout { source, .. }
+        | MigrateError::Transaction { source, .. }
+        | MigrateError::Bookkeeping(source) => source,
+        _ => return false,
+    };
+    let code = source.as_database_error().and_then(|e| e.code());
+
+    code.is_some_and(|code| RETRYABLE_CODES.contains(&code.as_ref()))
+}
+
+/// A number in `[0, 1)` from the randomly seeded std hasher, which is good
+/// enough for jitter.
+fn random_fraction() -> f64 {
+    let bits = RandomState::new().build_hasher().finish();
+    (bits >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delay() {
+        let policy = RetryPolicy {
+            max_retries: 10,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(1000),
+        };
+
+        for (retry, full) in [
+            (1, 100),
+            (2, 200),
+            (3, 400),
+            (4, 800),
+            (5, 1000),
+            (10, 1000),
+        ] {
+            let delay = policy.delay(retry);
+            assert!(delay <= Duration::from_millis(full));
+            assert!(delay >= Duration::from_millis(full / 2));
+        }
+
+        // The exponent saturates instead of overflowing
+        for retry in [32, 64, u32::MAX] {
+            assert!(policy.delay(retry) <= policy.max_delay);
+        }
+    }
+
+    #[derive(Debug)]
+    struct SqlState(&'static str);
+
+    impl std::fmt::Display for SqlState {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "error with SQLSTATE {}", self.0)
+        }
+    }
+
+    impl std::error::Error for SqlState {}
+
+    impl sqlx::error::DatabaseError for SqlState {
+        fn message(&self) -> &str {
+            "error"
+        }
+
+        fn code(&self) -> Option<std::borrow::Cow<'_, str>> {
+            Some(self.0.into())
+        }
+
+        fn as_error(&self) -> &(dyn std::error::Error + Send + Sync + 'static) {
+            self
+        }
+
+        fn as_error_mut(&mut self) -> &mut (dyn std::error::Error + Send + Sync + 'static) {
+            self
+        }
+
+        fn into_error(self: Box<Self>) -> Box<dyn std::error::Error + Send + Sync + 'static> {
+            self
+        }
+
+        fn kind(&self) -> sqlx::error::ErrorKind {
+            sqlx::error::ErrorKind::Other
+        }
+    }
+
+    fn execution_error(code: &'static str) -> MigrateError {
+        MigrateError::Execution {
+            file: "test1_up.sql".to_string(),
+            statement: 1,
+            source: sqlx::Error::Database(Box::new(SqlState(code))),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_should_retry() {
+        let policy = RetryPolicy {
+            max_retries: 2,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(1),
+        };
+
+        let mut retries = policy.retries("test1_up.sql");
+        let e = MigrateError::Invalid("not retryable".to_string());
+        assert!(!retries.should_retry(&e).await);
+        assert_eq!(retries.retry, 0);
+
+        let e = MigrateError::Bookkeeping(sqlx::Error::RowNotFound);
+        assert!(!retries.should_retry(&e).await);
+        assert!(!retries.should_retry(&execution_error("42703")).await);
+        assert_eq!(retries.retry, 0);
+
+        for code in RETRYABLE_CODES {
+            let e = execution_error(code);
+            let mut retries = policy.retries("test1_up.sql");
+            assert!(retries.should_retry(&e).await);
+            assert!(retries.should_retry(&e).await);
+            assert_eq!(retries.retry, 2);
+            assert!(!retries.should_retry(&e).await);
+            assert_eq!(retries.retry, 2);
+        }
+    }
+}
@@ -0,0 +1,208 @@
+use sqlx::postgres::PgConnection;
+use sqlx::{Connection, Row};
+
+use crate::error::MigrateError;
+use crate::file::migration_version;
+
+/// Layout of the migration table that this version of migrate writes.
+///
+/// 1. `id`, `up_file` and `down_file` only
+/// 2. adds `version`, `applied_at`, checksums, `transactional`, timing and
+///    who applied each migration
+/// 3. adds `batch`
+pub const SCHEMA_VERSION: i32 = 3;
+
+const COMMENT_PREFIX: &str = "migrate schema version ";
+
+/// Schema version of `table`, or `None` if the table does not exist.
+///
+/// The version is kept in the table comment. Tables without one were created
+/// before versioning and are treated as version 1.
+pub async fn schema_version(
+    conn: &mut PgConnection,
+    table: &str,
+) -> Result<Option<i32>, MigrateError> {
+    let row = sqlx::query(
+        "SELECT to_regclass($1) IS NOT NULL AS exists,
+            obj_description(to_regclass($1), 'pg_class') AS comment",
+    )
+    .bind(table)
+    .fetch_one(conn)
+    .await?;
+
+    if !row.get::<bool, _>("exists") {
+        return Ok(None);
+    }
+    let comment: Option<String> = row.get("comment");
+
+    Ok(Some(
+        comment
+            .as_deref()
+            .and_then(|c| c.strip_prefix(COMMENT_PREFIX))
+            .and_then(|v| v.trim().parse().ok())
+            .unwrap_or(1),
+    ))
+}
+
+pub(crate) async fn set_schema_version(
+    conn: &mut PgConnection,
+    table: &str,
+    version: i32,
+) -> Result<(), MigrateError> {
+    let query = format!(
+        "COMMENT ON TABLE {} IS '{}{}'",
+        table, COMMENT_PREFIX, version
+    );
+    sqlx::query(&query).execute(conn).await?;
+    Ok(())
+}
+
+/// Bring an existing migration table up to `SCHEMA_VERSION` in one
+/// transaction. Does nothing if the table is missing or already current.
+pub async fn upgrade_migration_table(
+    conn: &mut PgConnection,
+    table: &str,
+) -> Result<(), MigrateError> {
+    let Some(current) = schema_version(conn, table).await? else {
+        return Ok(());
+    };
+    if current > SCHEMA_VERSION {
+        return Err(MigrateError::Invalid(format!(
+            "{} uses schema version {}, but this version of migrate only supports up to {}",
+            table, current, SCHEMA_VERSION
+        )));
+    }
+    if current == SCHEMA_VERSION {
+        return Ok(());
+    }
+
+    println!(
+        "Upgrading {} from schema version {} to {}",
+        table, current, SCHEMA_VERSION
+    );
+    let mut tx = conn.begin().await?;
+    for version in current + 1..=SCHEMA_VERSION {
+        upgrade_to(&mut tx, table, version).await?;
+    }
+    set_schema_version(&mut tx, table, SCHEMA_VERSION).await?;
+    tx.commit().await?;
+
+    Ok(())
+}
+
+async fn upgrade_to(
+    conn: &mut PgConnection,
+    table: &str,
+    version: i32,
+) -> Result<(), MigrateError> {
+    match version {
+        2 => {
+            // Tables created by earlier builds may already have some of these columns.
+            // Existing rows keep NULL rather than getting the defaults, since
+            // nobody knows when or by whom they were applied.
+            let query = format!(
+                "ALTER TABLE {}
+                ADD COLUMN IF NOT EXISTS version VARCHAR(400),
+                ADD COLUMN IF NOT EXISTS applied_at TIMESTAMP WITH TIME ZONE,
+                ADD COLUMN IF NOT EXISTS up_checksum VARCHAR(64),
+                ADD COLUMN IF NOT EXISTS down_checksum VARCHAR(64),
+                ADD COLUMN IF NOT EXISTS transactional BOOLEAN NOT NULL DEFAULT TRUE,
+                ADD COLUMN IF NOT EXISTS duration_ms BIGINT,
+                ADD COLUMN IF NOT EXISTS applied_by VARCHAR(400),
+                ADD COLUMN IF NOT EXISTS os_user VARCHAR(400),
+                ADD COLUMN IF NOT EXISTS hostname VARCHAR(400),
+                ADD COLUMN IF NOT EXISTS tool_version VARCHAR(64),
+                ALTER COLUMN applied_at SET DEFAULT CURRENT_TIMESTAMP,
+                ALTER COLUMN applied_by SET DEFAULT CURRENT_USER",
+                table
+            );
+            sqlx::query(&query).execute(&mut *conn).await?;
+
+            let query = format!("SELECT id, up_file FROM {} WHERE version IS NULL", table);
+            let rows = sqlx::query(&query).fetch_all(&mut *conn).await?;
+            let query = format!("UPDATE {} SET version = $1 WHERE id = $2", table);
+            for row in rows {
+                let id: i32 = row.get("id");
+                let up_file: String = row.get("up_file");
+                sqlx::query(&query)
+                    .bind(migration_version(&up_file))
+                    .bind(id)
+                    .execute(&mut *conn)
+                    .await?;
+            }
+        }
+        3 => {
+            // Nobody knows which older migrations were applied together, so
+            // each one becomes a batch of its own
+            let queries = [
+                format!(
+                    "ALTER TABLE {} ADD COLUMN IF NOT EXISTS batch INTEGER",
+                    table
+                ),
+                format!(
+                    "UPDATE {0} AS m SET batch = n.row
+                    FROM (SELECT id, row_number() OVER (ORDER BY id) AS row FROM {0}) AS n
+                    WHERE m.id = n.id AND m.batch IS NULL",
+                    table
+                ),
+            ];
+            for query in queries {
+                sqlx::query(&query).execute(&mut *conn).await?;
+            }
+        }
+        _ => unreachable!("no upgrade to schema version {}", version),
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{db_connection, get_applied_migrations};
+
+    #[tokio::test]
+    async fn test_upgrade_migration_table() {
+        let mut conn = db_connection().await.unwrap();
+        let table = "_test_upgrade_migrations";
+        let setup = [
+            format!("DROP TABLE IF EXISTS {}", table),
+            format!(
+                "CREATE TABLE {} (
+                id SERIAL PRIMARY KEY,
+                up_file VARCHAR(400) NOT NULL,
+                down_file VARCHAR(400) NOT NULL
+            )",
+                table
+            ),
+            format!(
+                "INSERT INTO {} (up_file, down_file) VALUES ('test1_up.sql', 'test1_down.sql')",
+                table
+            ),
+        ];
+        for query in setup {
+            sqlx::query(&query).execute(&mut conn).await.unwrap();
+        }
+        assert_eq!(schema_version(&mut conn, table).await.unwrap(), Some(1));
+
+        upgrade_migration_table(&mut conn, table).await.unwrap();
+
+        assert_eq!(
+            schema_version(&mut conn, table).await.unwrap(),
+            Some(SCHEMA_VERSION)
+        );
+        let query = format!("SELECT version, batch FROM {}", table);
+        let row = sqlx::query(&query).fetch_one(&mut conn).await.unwrap();
+        assert_eq!(row.get::<String, _>("version"), "test1");
+        assert_eq!(row.get::<i32, _>("batch"), 1);
+        let applied = get_applied_migrations(&mut conn, table).await.unwrap();
+        assert!(applied[0].transactional && applied[0].applied_at.is_none());
+
+        // Upgrading a current table is a no-op
+        upgrade_migration_table(&mut conn, table).await.unwrap();
+
+        let query = format!("DROP TABLE {}", table);
+        sqlx::query(&query).execute(&mut conn).await.unwrap();
+        assert_eq!(schema_version(&mut conn, table).await.unwrap(), None);
+    }
+}
@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+use std::io;
+
+use crate::embed::EmbeddedMigration;
+use crate::error::MigrateError;
+use crate::file::{
+    checksum, file_checksum, get_all_migration_files, get_all_migrations, read_sql_file, Migration,
+};
+use crate::Migrations;
+
+/// Where migration files are read from.
+pub enum MigrationSource {
+    /// `*_up.sql` and `*_down.sql` files in a directory
+    Dir(String),
+    /// Migrations compiled into the binary by `embedded_migrations!`
+    Embedded(&'static [EmbeddedMigration]),
+}
+
+impl MigrationSource {
+    /// Up/down pairs ordered by version.
+    pub fn migrations(&self) -> Result<Vec<Migration>, MigrateError> {
+        match self {
+            MigrationSource::Dir(dir) => get_all_migrations(dir),
+            MigrationSource::Embedded(embedded) => {
+                let mut migrations: Vec<Migration> = embedded
+                    .iter()
+                    .map(|m| Migration {
+                        version: m.version.to_string(),
+                        up_file: m.up_file.to_string(),
+                        down_file: m.down_file.to_string(),
+                    })
+                    .collect();
+                migrations.sort_by(|a, b| a.up_file.cmp(&b.up_file));
+                Ok(migrations)
+            }
+        }
+    }
+
+    /// Up file names, sorted.
+    pub fn up_files(&self) -> Result<Vec<String>, MigrateError> {
+        match self {
+            MigrationSource::Dir(dir) => get_all_migration_files(dir, Migrations::UP),
+            MigrationSource::Embedded(_) => {
+                Ok(self.migrations()?.into_iter().map(|m| m.up_file).collect())
+            }
+        }
+    }
+
+    /// Contents of an up or down file.
+    pub fn read(&self, filename: &str) -> Result<String, MigrateError> {
+        match self {
+            MigrationSource::Dir(dir) => read_sql_file(&format!("{}/{}", dir, filename)),
+            MigrationSource::Embedded(embedded) => embedded
+                .iter()
+                .find_map(|m| {
+                    if m.up_file == filename {
+                        Some(m.up_sql.to_string())
+                    } else if m.down_file == filename {
+                        Some(m.down_sql.to_string())
+                    } else {
+                        None
+                    }
+                })
+                .ok_or_else(|| MigrateError::io(filename, io::ErrorKind::NotFound.into())),
+        }
+    }
+
+    /// Checksum of every up and down file, keyed by file name.
+    pub fn checksums(&self) -> Result<HashMap<String, String>, MigrateError> {
+        let mut checksums = HashMap::new();
+
+        match self {
+            MigrationSource::Dir(dir) => {
+                let mut files = get_all_migration_files(dir, Migrations::UP)?;
+                files.append(&mut get_all_migration_files(dir, Migrations::DOWN)?);
+                for file in files {
+                    let checksum = file_checksum(&format!("{}/{}", dir, file))?;
+                    checksums.insert(file, checksum);
+                }
+            }
+            MigrationSource::Embedded(embedded) => {
+                for m in embedded.iter() {
+                    checksums.insert(m.up_file.to_string(), checksum(m.up_sql.as_bytes()));
+                    checksums.insert(m.down_file.to_string(), checksum(m.down_sql.as_bytes()));
+                }
+            }
+        }
+
+        Ok(checksums)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static EMBEDDED: &[EmbeddedMigration] = &[
+        EmbeddedMigration {
+            version: "test2",
+            up_file: "test2_up.sql",
+            down_file: "test2_down.sql",
+            up_sql: "CREATE TABLE t2 (id INT);",
+            down_sql: "DROP TABLE t2;",
+        },
+        EmbeddedMigration {
+            version: "test1",
+            up_file: "test1_up.sql",
+            down_file: "test1_down.sql",
+            up_sql: "CREATE TABLE t1 (id INT);",
+            down_sql: "DROP TABLE t1;",
+        },
+    ];
+
+    #[test]
+    fn test_embedded_source() {
+        let source = MigrationSource::Embedded(EMBEDDED);
+
+        assert_eq!(
+            source.up_files().unwrap(),
+            vec!["test1_up.sql", "test2_up.sql"]
+        );
+        assert_eq!(source.read("test2_down.sql").unwrap(), "DROP TABLE t2;");
+        assert!(source.read("test3_up.sql").is_err());
+        assert_eq!(
+            source.checksums().unwrap()["test1_up.sql"],
+            checksum(b"CREATE TABLE t1 (id INT);")
+        );
+    }
+
+    #[test]
+    fn test_dir_source() {
+        let source = MigrationSource::Dir("./test".to_string());
+
+        assert_eq!(
+            source.migrations().unwrap(),
+            get_all_migrations("./test").unwrap()
+        );
+        assert_eq!(
+            source.checksums().unwrap()["test1_down.sql"],
+            file_checksum("./test/test1_down.sql").unwrap()
+        );
+    }
+}
@@ -0,0 +1,272 @@
+use chrono::{DateTime, Local, Utc};
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::db::AppliedMigration;
+
+#[derive(Debug, PartialEq)]
+pub enum MigrationState {
+    Applied,
+    Modified,
+    Pending,
+    OutOfOrder,
+    FileMissing,
+}
+
+impl fmt::Display for MigrationState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let label = match self {
+            MigrationState::Applied => "applied",
+            MigrationState::Modified => "modified",
+            MigrationState::Pending => "pending",
+            MigrationState::OutOfOrder => "out-of-order",
+            MigrationState::FileMissing => "file missing",
+        };
+        f.pad(label)
+    }
+}
+
+#[derive(Debug)]
+pub struct MigrationStatus {
+    pub up_file: String,
+    pub state: MigrationState,
+    pub applied_at: Option<DateTime<Utc>>,
+    pub duration_ms: Option<i64>,
+    pub applied_by: Option<String>,
+    pub os_user: Option<String>,
+    pub hostname: Option<String>,
+    pub tool_version: Option<String>,
+    pub batch: Option<i32>,
+}
+
+impl MigrationStatus {
+    fn new(up_file: &str, state: MigrationState, applied: Option<&AppliedMigration>) -> Self {
+        MigrationStatus {
+            up_file: up_file.to_string(),
+            state,
+            applied_at: applied.and_then(|m| m.applied_at),
+            duration_ms: applied.and_then(|m| m.duration_ms),
+            applied_by: applied.and_then(|m| m.applied_by.clone()),
+            os_user: applied.and_then(|m| m.os_user.clone()),
+            hostname: applied.and_then(|m| m.hostname.clone()),
+            tool_version: applied.and_then(|m| m.tool_version.clone()),
+            batch: applied.and_then(|m| m.batch),
+        }
+    }
+
+    pub fn applied_at_label(&self) -> String {
+        match self.applied_at {
+            Some(applied_at) => applied_at
+                .with_timezone(&Local)
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string(),
+            None => "-".to_string(),
+        }
+    }
+
+    pub fn duration_label(&self) -> String {
+        match self.duration_ms {
+            Some(duration_ms) => format!("{} ms", duration_ms),
+            None => "-".to_string(),
+        }
+    }
+
+    /// Database role, followed by the OS user and host if they were recorded,
+    /// e.g. `app (deploy@web-1)`.
+    pub fn applied_by_label(&self) -> String {
+        let role = self.applied_by.as_deref().unwrap_or("-");
+        match (&self.os_user, &self.hostname) {
+            (Some(user), Some(host)) => format!("{} ({}@{})", role, user, host),
+            (Some(user), None) => format!("{} ({})", role, user),
+            (None, Some(host)) => format!("{} (@{})", role, host),
+            (None, None) => role.to_string(),
+        }
+    }
+}
+
+/// Compare the up files on disk with the rows in the migration table.
+///
+/// A file that is not applied but sorts before the newest applied migration
+/// is reported as out-of-order, since `up` would never pick it up.
+/// `modified` holds the file names returned by `find_modified_migrations`.
+pub fn compare_migrations(
+    up_files: &[String],
+    applied: &[AppliedMigration],
+    modified: &[String],
+) -> Vec<MigrationStatus> {
+    let newest_applied = applied.iter().map(|m| &m.up_file).max();
+
+    let mut statuses: Vec<MigrationStatus> = up_files
+        .iter()
+        .map(
+            |up_file| match applied.iter().find(|m| &m.up_file == up_file) {
+                Some(m) => {
+                    let state = if modified.contains(&m.up_file) || modified.contains(&m.down_file)
+                    {
+                        MigrationState::Modified
+                    } else {
+                        MigrationState::Applied
+                    };
+                    MigrationStatus::new(up_file, state, Some(m))
+                }
+                None => {
+                    let state = match newest_applied {
+                        Some(newest) if up_file < newest => MigrationState::OutOfOrder,
+                        _ => MigrationState::Pending,
+                    };
+                    MigrationStatus::new(up_file, state, None)
+                }
+            },
+        )
+        .collect();
+
+    for m in applied {
+        if !up_files.contains(&m.up_file) {
+            statuses.push(MigrationStatus::new(
+                &m.up_file,
+                MigrationState::FileMissing,
+                Some(m),
+            ));
+        }
+    }
+
+    statuses.sort_by(|a, b| a.up_file.cmp(&b.up_file));
+    statuses
+}
+
+/// File names of applied migrations whose contents no longer match the
+/// checksum recorded when they were applied.
+///
+/// `checksums` maps each migration file that still exists to the checksum of
+/// its current contents. Rows without a checksum and files that no longer
+/// exist are skipped.
+pub fn find_modified_migrations(
+    applied: &[AppliedMigration],
+    checksums: &HashMap<String, String>,
+) -> Vec<String> {
+    let mut modified = vec![];
+
+    for m in applied {
+        let files = [
+            (&m.up_file, &m.up_checksum),
+            (&m.down_file, &m.down_checksum),
+        ];
+        for (filename, checksum) in files {
+            let Some(checksum) = checksum else {
+                continue;
+            };
+            if checksums
+                .get(filename)
+                .is_some_and(|current| current != checksum)
+            {
+                modified.push(filename.clone());
+            }
+        }
+    }
+
+    modified
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn applied(up_file: &str) -> AppliedMigration {
+        AppliedMigration {
+            up_file: up_file.to_string(),
+            down_file: up_file.replace("_up.sql", "_down.sql"),
+            applied_at: None,
+            up_checksum: None,
+            down_checksum: None,
+            transactional: true,
+            duration_ms: None,
+            applied_by: None,
+            os_user: None,
+            hostname: None,
+            tool_version: None,
+            batch: None,
+        }
+    }
+
+    #[test]
+    fn test_compare_migrations() {
+        let up_files = vec![
+            "test1_up.sql".to_string(),
+            "test2_up.sql".to_string(),
+            "test3_up.sql".to_string(),
+            "test5_up.sql".to_string(),
+        ];
+        let applied = vec![
+            applied("test1_up.sql"),
+            applied("test3_up.sql"),
+            applied("test4_up.sql"),
+        ];
+
+        let modified = vec!["test3_down.sql".to_string()];
+
+        let statuses = compare_migrations(&up_files, &applied, &modified);
+        let result: Vec<(&str, &MigrationState)> = statuses
+            .iter()
+            .map(|s| (s.up_file.as_str(), &s.state))
+            .collect();
+
+        assert_eq!(
+            result,
+            vec![
+                ("test1_up.sql", &MigrationState::Applied),
+                ("test2_up.sql", &MigrationState::OutOfOrder),
+                ("test3_up.sql", &MigrationState::Modified),
+                ("test4_up.sql", &MigrationState::FileMissing),
+                ("test5_up.sql", &MigrationState::Pending),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compare_migrations_without_applied() {
+        let up_files = vec!["test1_up.sql".to_string(), "test2_up.sql".to_string()];
+
+        let statuses = compare_migrations(&up_files, &[], &[]);
+
+        assert!(statuses
+            .iter()
+            .all(|s| s.state == MigrationState::Pending && s.applied_at.is_none()));
+    }
+
+    #[test]
+    fn test_status_labels() {
+        let mut m = applied("test1_up.sql");
+        m.duration_ms = Some(12);
+        m.applied_by = Some("app".to_string());
+        m.os_user = Some("deploy".to_string());
+        m.hostname = Some("web-1".to_string());
+
+        let status = MigrationStatus::new(&m.up_file, MigrationState::Applied, Some(&m));
+        assert_eq!(status.duration_label(), "12 ms");
+        assert_eq!(status.applied_by_label(), "app (deploy@web-1)");
+
+        let status = MigrationStatus::new("test2_up.sql", MigrationState::Pending, None);
+        assert_eq!(status.duration_label(), "-");
+        assert_eq!(status.applied_by_label(), "-");
+    }
+
+    #[test]
+    fn test_find_modified_migrations() {
+        let checksums = HashMap::from([
+            ("test1_up.sql".to_string(), "1".repeat(64)),
+            ("test1_down.sql".to_string(), "2".repeat(64)),
+            ("test2_up.sql".to_string(), "3".repeat(64)),
+        ]);
+        let mut test1 = applied("test1_up.sql");
+        test1.up_checksum = Some("1".repeat(64));
+        test1.down_checksum = Some("2".repeat(64));
+        let mut test2 = applied("test2_up.sql");
+        test2.up_checksum = Some("0".repeat(64));
+        let mut missing = applied("missing_up.sql");
+        missing.up_checksum = Some("0".repeat(64));
+
+        let modified = find_modified_migrations(&[test1, test2, missing], &checksums);
+
+        assert_eq!(modified, vec!["test2_up.sql"]);
+    }
+}